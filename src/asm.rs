@@ -0,0 +1,866 @@
+//! An assembler for a subset of the Octo language.
+//!
+//! Supported are labels (`: name`), `:const`, `:alias`, `:macro`, `:byte`,
+//! `loop`/`while`/`again`, `if ... then` and `if ... begin ... else ... end`,
+//! raw bytes for sprite data and the Chip8, SUPER-CHIP and XO-CHIP
+//! statements. Programs start executing at the `main` label.
+
+use crate::rom;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// How deeply macros can expand into other macros, so a macro that expands
+/// itself is an error rather than looping forever.
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// An error in the source, `line` and `column` start from 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    /// the number of macro expansions the token came from, 0 in the source
+    depth: usize,
+}
+
+impl Token {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, AsmError> {
+        Err(AsmError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        })
+    }
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (n, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut chars = line.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            let mut end = start + c.len_utf8();
+            while let Some((i, c)) = chars.peek().copied() {
+                if c.is_whitespace() {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push_back(Token {
+                text: line[start..end].to_string(),
+                line: n + 1,
+                column: line[..start].chars().count() + 1,
+                depth: 0,
+            });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i32::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+#[derive(Clone, Copy, Debug)]
+enum FixupKind {
+    /// the low 12 bits of the opcode at the offset
+    Addr12,
+    /// the 16 bits at the offset
+    Addr16,
+}
+
+#[derive(Debug)]
+struct Fixup {
+    offset: usize,
+    kind: FixupKind,
+    label: Token,
+}
+
+#[derive(Debug)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Open `loop` or `begin` blocks, holding the offsets of jumps to patch.
+#[derive(Debug)]
+enum Block {
+    Loop { start: u16, breaks: Vec<usize> },
+    If { jump: usize, has_else: bool },
+}
+
+/// A condition compiled to the skip opcodes that skip the next instruction
+/// when the condition is true and when it is false.
+struct Condition {
+    skip_if_true: u16,
+    skip_if_false: u16,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, i32>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<(Token, Block)>,
+    /// position of the end of the source, used for errors at EOF
+    end: Token,
+}
+
+impl Assembler {
+    fn new(source: &str) -> Self {
+        let tokens = tokenize(source);
+        let lines = source.lines().count();
+        Self {
+            tokens,
+            rom: vec![],
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: vec![],
+            blocks: vec![],
+            end: Token {
+                text: String::new(),
+                line: lines.max(1),
+                column: source.lines().last().map_or(0, |l| l.chars().count()) + 1,
+                depth: 0,
+            },
+        }
+    }
+
+    fn here(&self) -> u16 {
+        rom::BASE_ADDRESS.wrapping_add(self.rom.len() as u16)
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(tok) => Ok(tok),
+            None => self.end.error("unexpected end of input"),
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|t| t.text == text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let tok = self.next()?;
+        if tok.text != text {
+            return tok.error(format!("expected {}, found {}", text, tok.text));
+        }
+        Ok(tok)
+    }
+
+    fn emit_u8(&mut self, byte: u8) {
+        self.rom.push(byte);
+    }
+
+    fn emit(&mut self, opcode: u16) {
+        self.rom.extend_from_slice(&opcode.to_be_bytes());
+    }
+
+    /// Point the jump at `offset` to `addr`, `tok` is the statement the jump
+    /// was for.
+    fn patch(&mut self, tok: &Token, offset: usize, addr: u16) -> Result<(), AsmError> {
+        let addr = jump_target(tok, addr)?;
+        let opcode = u16::from_be_bytes([self.rom[offset], self.rom[offset + 1]]);
+        let opcode = (opcode & 0xF000) | addr;
+        self.rom[offset..offset + 2].copy_from_slice(&opcode.to_be_bytes());
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let tok = self.next()?;
+        if parse_number(&tok.text).is_some() || parse_register(&tok.text).is_some() {
+            return tok.error(format!("invalid name: {}", tok.text));
+        }
+        Ok(tok)
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let tok = self.next()?;
+        self.register_of(&tok)
+    }
+
+    fn register_of(&self, tok: &Token) -> Result<u8, AsmError> {
+        match parse_register(&tok.text).or_else(|| self.aliases.get(&tok.text).copied()) {
+            Some(r) => Ok(r),
+            None => tok.error(format!("expected a register, found {}", tok.text)),
+        }
+    }
+
+    fn is_register(&self, tok: &Token) -> bool {
+        self.register_of(tok).is_ok()
+    }
+
+    /// A number or constant.
+    fn number_of(&self, tok: &Token) -> Option<i32> {
+        parse_number(&tok.text).or_else(|| self.consts.get(&tok.text).copied())
+    }
+
+    fn number(&mut self, max: i32) -> Result<i32, AsmError> {
+        let tok = self.next()?;
+        match self.number_of(&tok) {
+            Some(n) if (-128..=max).contains(&n) => Ok(n),
+            Some(n) => tok.error(format!("{} is out of range", n)),
+            None => tok.error(format!("expected a number, found {}", tok.text)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        Ok(self.number(0xFF)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u16, AsmError> {
+        let tok = self.next()?;
+        match self.number_of(&tok) {
+            Some(n) if (0..=0xF).contains(&n) => Ok(n as u16),
+            _ => tok.error(format!(
+                "expected a number from 0 to 15, found {}",
+                tok.text
+            )),
+        }
+    }
+
+    /// Emit `opcode` with an address operand, labels that aren't defined yet
+    /// are patched in once the whole program has been read.
+    fn emit_addr(&mut self, opcode: u16, kind: FixupKind) -> Result<(), AsmError> {
+        let tok = self.next()?;
+        let max = match kind {
+            FixupKind::Addr12 => 0xFFF,
+            FixupKind::Addr16 => 0xFFFF,
+        };
+        let offset = match kind {
+            FixupKind::Addr12 => self.rom.len(),
+            FixupKind::Addr16 => self.rom.len() + 2,
+        };
+        let addr = match self
+            .number_of(&tok)
+            .or_else(|| self.labels.get(&tok.text).map(|a| *a as i32))
+        {
+            Some(n) if (0..=max).contains(&n) => n as u16,
+            Some(n) => return tok.error(format!("address 0x{:X} is out of range", n)),
+            None => {
+                self.fixups.push(Fixup {
+                    offset,
+                    kind,
+                    label: tok,
+                });
+                0
+            }
+        };
+        match kind {
+            FixupKind::Addr12 => self.emit(opcode | addr),
+            FixupKind::Addr16 => {
+                self.emit(opcode);
+                self.emit(addr);
+            }
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let x = self.register()? as u16;
+        let op = self.next()?;
+        let (true_op, false_op) = match op.text.as_str() {
+            "key" => return Ok(skip_pair(0xE09E | x << 8, 0xE0A1 | x << 8)),
+            "-key" => return Ok(skip_pair(0xE0A1 | x << 8, 0xE09E | x << 8)),
+            "==" => (true, false),
+            "!=" => (false, true),
+            _ => return op.error(format!("unsupported comparison: {}", op.text)),
+        };
+        let rhs = self.next()?;
+        let (eq, ne) = if self.is_register(&rhs) {
+            let y = self.register_of(&rhs)? as u16;
+            (0x5000 | x << 8 | y << 4, 0x9000 | x << 8 | y << 4)
+        } else {
+            self.tokens.push_front(rhs);
+            let n = self.byte()? as u16;
+            (0x3000 | x << 8 | n, 0x4000 | x << 8 | n)
+        };
+        Ok(if true_op && !false_op {
+            skip_pair(eq, ne)
+        } else {
+            skip_pair(ne, eq)
+        })
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AsmError> {
+        let x = x as u16;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        if self.is_register(&rhs) {
+            let y = self.register_of(&rhs)? as u16;
+            let n = match op.text.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return op.error(format!("unknown operator: {}", op.text)),
+            };
+            self.emit(0x8000 | x << 8 | y << 4 | n);
+            return Ok(());
+        }
+        match (op.text.as_str(), rhs.text.as_str()) {
+            (":=", "random") => {
+                let n = self.byte()? as u16;
+                self.emit(0xC000 | x << 8 | n);
+            }
+            (":=", "delay") => self.emit(0xF007 | x << 8),
+            (":=", "key") => self.emit(0xF00A | x << 8),
+            (":=", _) | ("+=", _) | ("-=", _) => {
+                self.tokens.push_front(rhs);
+                let n = self.byte()?;
+                match op.text.as_str() {
+                    ":=" => self.emit(0x6000 | x << 8 | n as u16),
+                    "+=" => self.emit(0x7000 | x << 8 | n as u16),
+                    _ => self.emit(0x7000 | x << 8 | n.wrapping_neg() as u16),
+                }
+            }
+            _ => return op.error(format!("unknown operator: {}", op.text)),
+        }
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), AsmError> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            "+=" => {
+                let x = self.register()? as u16;
+                self.emit(0xF01E | x << 8);
+            }
+            ":=" => {
+                if self.peek_is("hex") || self.peek_is("bighex") {
+                    let font = self.next()?;
+                    let x = self.register()? as u16;
+                    let n = if font.text == "hex" { 0x29 } else { 0x30 };
+                    self.emit(0xF000 | x << 8 | n);
+                } else if self.peek_is("long") {
+                    self.next()?;
+                    self.emit_addr(0xF000, FixupKind::Addr16)?;
+                } else {
+                    self.emit_addr(0xA000, FixupKind::Addr12)?;
+                }
+            }
+            _ => return op.error(format!("unknown operator: {}", op.text)),
+        }
+        Ok(())
+    }
+
+    /// `save` and `load`, optionally with an XO-CHIP register range.
+    fn save_load(&mut self, single: u16, range: u16) -> Result<(), AsmError> {
+        let x = self.register()? as u16;
+        if self.peek_is("-") {
+            self.next()?;
+            let y = self.register()? as u16;
+            self.emit(0x5000 | x << 8 | y << 4 | range);
+        } else {
+            self.emit(0xF000 | x << 8 | single);
+        }
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.name()?;
+        let mut args = vec![];
+        loop {
+            let tok = self.next()?;
+            if tok.text == "{" {
+                break;
+            }
+            args.push(tok.text);
+        }
+        let mut body = vec![];
+        let mut depth = 1;
+        loop {
+            let tok = self.next()?;
+            match tok.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(tok);
+        }
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, tok: &Token) -> Result<(), AsmError> {
+        let name = tok.text.as_str();
+        if tok.depth >= MAX_MACRO_DEPTH {
+            return tok.error(format!("{} expands too deeply, is it recursive?", name));
+        }
+        let nargs = self.macros[name].args.len();
+        let mut values = HashMap::new();
+        for i in 0..nargs {
+            let arg = self.next()?;
+            values.insert(self.macros[name].args[i].clone(), arg.text);
+        }
+        let body = &self.macros[name].body;
+        for body_tok in body.iter().rev() {
+            let mut body_tok = body_tok.clone();
+            if let Some(value) = values.get(&body_tok.text) {
+                body_tok.text = value.clone();
+            }
+            body_tok.depth = tok.depth + 1;
+            self.tokens.push_front(body_tok);
+        }
+        Ok(())
+    }
+
+    fn end_block(&mut self, tok: &Token) -> Result<(), AsmError> {
+        match self.blocks.pop() {
+            Some((_, Block::If { jump, .. })) => {
+                let here = self.here();
+                self.patch(tok, jump, here)
+            }
+            _ => tok.error("end without a matching begin"),
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let tok = self.next()?;
+        match tok.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                let here = self.here();
+                if self.labels.insert(name.text.clone(), here).is_some() {
+                    return name.error(format!("{} is already defined", name.text));
+                }
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number(0xFFFF)?;
+                self.consts.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let r = self.register()?;
+                self.aliases.insert(name.text, r);
+            }
+            ":macro" => self.define_macro()?,
+            ":byte" => {
+                let b = self.byte()?;
+                self.emit_u8(b);
+            }
+            "clear" => self.emit(0x00E0),
+            "return" | ";" => self.emit(0x00EE),
+            "exit" => self.emit(0x00FD),
+            "lores" => self.emit(0x00FE),
+            "hires" => self.emit(0x00FF),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(0x00C0 | n);
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(0x00D0 | n);
+            }
+            "scroll-right" => self.emit(0x00FB),
+            "scroll-left" => self.emit(0x00FC),
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(0xF001 | n << 8);
+            }
+            "audio" => self.emit(0xF002),
+            "jump" => self.emit_addr(0x1000, FixupKind::Addr12)?,
+            "jump0" => self.emit_addr(0xB000, FixupKind::Addr12)?,
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nibble()?;
+                self.emit(0xD000 | x << 8 | y << 4 | n);
+            }
+            "save" => self.save_load(0x55, 0x2)?,
+            "load" => self.save_load(0x65, 0x3)?,
+            "saveflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF075 | x << 8);
+            }
+            "loadflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF085 | x << 8);
+            }
+            "bcd" => {
+                let x = self.register()? as u16;
+                self.emit(0xF033 | x << 8);
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                let n = match tok.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.emit(0xF000 | x << 8 | n);
+            }
+            "i" => self.index_statement()?,
+            "if" => {
+                let cond = self.condition()?;
+                let then = self.next()?;
+                match then.text.as_str() {
+                    "then" => self.emit(cond.skip_if_false),
+                    "begin" => {
+                        self.emit(cond.skip_if_true);
+                        let jump = self.rom.len();
+                        self.emit(0x1000);
+                        self.blocks.push((
+                            tok,
+                            Block::If {
+                                jump,
+                                has_else: false,
+                            },
+                        ));
+                    }
+                    _ => return then.error(format!("expected then or begin, found {}", then.text)),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((
+                    start,
+                    Block::If {
+                        jump,
+                        has_else: false,
+                    },
+                )) => {
+                    let end_jump = self.rom.len();
+                    self.emit(0x1000);
+                    let here = self.here();
+                    self.patch(&tok, jump, here)?;
+                    self.blocks.push((
+                        start,
+                        Block::If {
+                            jump: end_jump,
+                            has_else: true,
+                        },
+                    ));
+                }
+                _ => return tok.error("else without a matching begin"),
+            },
+            "end" => self.end_block(&tok)?,
+            "loop" => {
+                let start = self.here();
+                self.blocks.push((
+                    tok,
+                    Block::Loop {
+                        start,
+                        breaks: vec![],
+                    },
+                ));
+            }
+            "while" => {
+                let cond = self.condition()?;
+                self.emit(cond.skip_if_true);
+                let jump = self.rom.len();
+                self.emit(0x1000);
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|(_, b)| matches!(b, Block::Loop { .. }))
+                {
+                    Some((_, Block::Loop { breaks, .. })) => breaks.push(jump),
+                    _ => return tok.error("while outside of a loop"),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((_, Block::Loop { start, breaks })) => {
+                    self.emit(0x1000 | jump_target(&tok, start)?);
+                    let here = self.here();
+                    for jump in breaks {
+                        self.patch(&tok, jump, here)?;
+                    }
+                }
+                _ => return tok.error("again without a matching loop"),
+            },
+            text if text.starts_with(':') => {
+                return tok.error(format!("unsupported directive: {}", text));
+            }
+            text if self.macros.contains_key(text) => self.expand_macro(&tok)?,
+            _ if self.is_register(&tok) => {
+                let x = self.register_of(&tok)?;
+                self.register_statement(x)?;
+            }
+            _ if self.number_of(&tok).is_some() => {
+                self.tokens.push_front(tok);
+                let b = self.byte()?;
+                self.emit_u8(b);
+            }
+            // anything else is a call to a subroutine
+            _ => {
+                self.tokens.push_front(tok);
+                self.emit_addr(0x2000, FixupKind::Addr12)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn assemble(mut self) -> Result<Vec<u8>, AsmError> {
+        // execution starts at main, jump to it unless it's the first thing
+        let starts_with_main =
+            self.tokens.len() >= 2 && self.tokens[0].text == ":" && self.tokens[1].text == "main";
+        if !starts_with_main {
+            let main = Token {
+                text: "main".to_string(),
+                line: 1,
+                column: 1,
+                depth: 0,
+            };
+            self.fixups.push(Fixup {
+                offset: 0,
+                kind: FixupKind::Addr12,
+                label: main,
+            });
+            self.emit(0x1000);
+        }
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        if let Some((tok, block)) = self.blocks.first() {
+            let message = match block {
+                Block::Loop { .. } => "loop without a matching again",
+                Block::If { .. } => "begin without a matching end",
+            };
+            return tok.error(message);
+        }
+        for fixup in &self.fixups {
+            let Some(addr) = self.labels.get(&fixup.label.text).copied() else {
+                return fixup
+                    .label
+                    .error(format!("{} is not defined", fixup.label.text));
+            };
+            let o = fixup.offset;
+            match fixup.kind {
+                FixupKind::Addr12 => {
+                    if addr > 0xFFF {
+                        return fixup.label.error(format!(
+                            "{} at 0x{:X} is out of range, use i := long",
+                            fixup.label.text, addr
+                        ));
+                    }
+                    self.rom[o] = (self.rom[o] & 0xF0) | (addr >> 8) as u8;
+                    self.rom[o + 1] = addr as u8;
+                }
+                FixupKind::Addr16 => {
+                    self.rom[o..o + 2].copy_from_slice(&addr.to_be_bytes());
+                }
+            }
+        }
+        Ok(self.rom)
+    }
+}
+
+/// Check that a jump made by `tok` can reach `addr`, jumps only have 12 bits.
+fn jump_target(tok: &Token, addr: u16) -> Result<u16, AsmError> {
+    if addr > 0xFFF {
+        return tok.error(format!("jump to 0x{:X} is out of range", addr));
+    }
+    Ok(addr)
+}
+
+fn skip_pair(skip_if_true: u16, skip_if_false: u16) -> Condition {
+    Condition {
+        skip_if_true,
+        skip_if_false,
+    }
+}
+
+/// Assemble Octo source into a ROM that loads at `rom::BASE_ADDRESS`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    Assembler::new(source).assemble()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_at(source: &str) -> (usize, usize) {
+        let e = assemble(source).unwrap_err();
+        (e.line, e.column)
+    }
+
+    #[test]
+    fn test_statements() {
+        let rom = assemble(
+            ": main
+              clear
+              v0 := 5
+              va += v1
+              i := hex v0
+              sprite v0 v1 5
+              return",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![0x00, 0xE0, 0x60, 0x05, 0x8A, 0x14, 0xF0, 0x29, 0xD0, 0x15, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_data() {
+        let rom = assemble(
+            ": sprite 0xFF 0b10000001
+             : main
+               i := sprite
+               draw
+               jump main
+             : draw ;",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x12, 0x04, // jump main
+                0xFF, 0x81, // sprite
+                0xA2, 0x02, // i := sprite
+                0x22, 0x0A, // draw
+                0x12, 0x04, // jump main
+                0x00, 0xEE, // ;
+            ]
+        );
+    }
+
+    #[test]
+    fn test_const_alias_macro() {
+        let rom = assemble(
+            ":const SPEED 3
+             :alias x v4
+             :macro move reg { reg += SPEED }
+             : main
+               move x",
+        )
+        .unwrap();
+        // 1202: jump main, as main isn't the first thing in the source
+        assert_eq!(rom, vec![0x12, 0x02, 0x74, 0x03]);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let rom = assemble(
+            ": main
+               loop
+                 v0 += 1
+                 while v0 != 10
+                 if v1 key then v2 := 1
+               again
+               if v0 == v1 begin v3 := 1 else v3 := 2 end",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x70, 0x01, // 0x200: v0 += 1
+                0x40, 0x0A, // 0x202: skip if v0 != 10
+                0x12, 0x0C, // 0x204: jump past again
+                0xE1, 0xA1, // 0x206: skip if v1 not pressed
+                0x62, 0x01, // 0x208: v2 := 1
+                0x12, 0x00, // 0x20A: again
+                0x50, 0x10, // 0x20C: skip if v0 == v1
+                0x12, 0x14, // 0x20E: jump to else
+                0x63, 0x01, // 0x210: v3 := 1
+                0x12, 0x16, // 0x212: jump to end
+                0x63, 0x02, // 0x214: v3 := 2
+            ]
+        );
+    }
+
+    #[test]
+    fn test_xo_chip() {
+        let rom = assemble(
+            ": main
+               i := long data
+               save v1 - v3
+               plane 3
+             : data",
+        )
+        .unwrap();
+        assert_eq!(rom, vec![0xF0, 0x00, 0x02, 0x08, 0x51, 0x32, 0xF3, 0x01]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error_at(": main\n  v0 := 256"), (2, 9));
+        assert_eq!(error_at(": main\n  jump nowhere"), (2, 8));
+        assert_eq!(error_at(": main\n  vz += 1"), (2, 3));
+        assert_eq!(error_at(": main\n  loop v0 += 1"), (2, 3));
+        assert_eq!(error_at("clear"), (1, 1));
+        assert_eq!(
+            assemble(": main\n  i :=").unwrap_err().message,
+            "unexpected end of input"
+        );
+    }
+
+    #[test]
+    fn test_recursive_macro() {
+        let e = assemble(":macro forever { v0 += 1 forever }\n: main\n  forever").unwrap_err();
+        assert_eq!(e.message, "forever expands too deeply, is it recursive?");
+        assert_eq!((e.line, e.column), (1, 26));
+        // macros can still use other macros
+        let rom =
+            assemble(":macro inc { v0 += 1 }\n:macro twice { inc inc }\n: main twice").unwrap();
+        assert_eq!(rom, vec![0x12, 0x02, 0x70, 0x01, 0x70, 0x01]);
+    }
+
+    #[test]
+    fn test_jump_out_of_range() {
+        // the loop starts at 0x1000, past what a jump can reach
+        let padding = "0 ".repeat(0x1000 - 0x200);
+        let e = assemble(&format!(": main {}loop again", padding)).unwrap_err();
+        assert_eq!(e.message, "jump to 0x1000 is out of range");
+        let e = assemble(&format!(": main if v0 == 0 begin {}end", padding)).unwrap_err();
+        assert_eq!(e.message, "jump to 0x1004 is out of range");
+    }
+}
@@ -0,0 +1,331 @@
+use crate::cpu::AUDIO_PATTERN_SIZE;
+use crate::machine::{Machine, TARGET_FPS};
+
+/// Number of samples (bits) in an XO-CHIP audio pattern.
+const PATTERN_BITS: f32 = (AUDIO_PATTERN_SIZE * 8) as f32;
+
+/// The rate at which XO-CHIP pattern bits are played for a given pitch.
+fn pattern_rate(pitch: u8) -> f32 {
+    4000. * 2f32.powf((pitch as f32 - 64.) / 48.)
+}
+
+/// How long the buzzer takes to fade in and out, cutting a wave off mid cycle
+/// clicks.
+const ENVELOPE_SECONDS: f32 = 0.002;
+
+/// Lowest and highest frequencies the tone can be set to, in Hz.
+pub const MIN_FREQUENCY: f32 = 20.;
+pub const MAX_FREQUENCY: f32 = 20000.;
+
+/// Shape of the tone played when the program hasn't loaded an XO-CHIP pattern.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+    /// random levels, changing once per period of the tone
+    Noise,
+}
+
+impl Waveform {
+    const ALL: [Waveform; 5] = [
+        Waveform::Square,
+        Waveform::Sine,
+        Waveform::Triangle,
+        Waveform::Sawtooth,
+        Waveform::Noise,
+    ];
+
+    /// The waveform after this one, wrapping around.
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|w| *w == self).unwrap();
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+
+    /// Level between -1 and 1 at `phase`, a fraction of the period. `noise`
+    /// is the level to hold for noise.
+    fn level(self, phase: f32, noise: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase <= 0.5 {
+                    1.
+                } else {
+                    -1.
+                }
+            }
+            Waveform::Sine => (phase * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1. - 4. * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2. * phase - 1.,
+            Waveform::Noise => noise,
+        }
+    }
+}
+
+impl std::fmt::Display for Waveform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Sawtooth => "sawtooth",
+            Waveform::Noise => "noise",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The default tone, and how loud the buzzer plays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    /// in Hz
+    pub frequency: f32,
+    /// from 0 to 1
+    pub volume: f32,
+}
+
+impl Tone {
+    /// The tone with its frequency and volume clamped to their ranges.
+    pub fn clamped(self) -> Self {
+        Self {
+            frequency: self.frequency.clamp(MIN_FREQUENCY, MAX_FREQUENCY),
+            volume: self.volume.clamp(0., 1.),
+            ..self
+        }
+    }
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Square,
+            frequency: 150.,
+            volume: 0.25,
+        }
+    }
+}
+
+/// Generates the buzzer's output one sample at a time, for playing through a
+/// sound card or writing to a file.
+#[derive(Clone, Debug)]
+pub struct Synth {
+    tone: Tone,
+    phase_inc: f32,
+    phase: f32,
+    /// xorshift state for noise
+    noise_state: u32,
+    noise: f32,
+    /// XO-CHIP audio pattern, plays instead of the tone when set
+    pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    pattern_inc: f32,
+    pattern_pos: f32,
+    sample_rate: f32,
+    /// samples left before the sound timer runs out
+    remaining: u32,
+    /// envelope level, from 0 to 1
+    gain: f32,
+    /// change in `gain` per sample while fading
+    gain_step: f32,
+}
+
+impl Synth {
+    pub fn new(tone: Tone, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        Self {
+            tone,
+            phase_inc: tone.frequency / sample_rate,
+            phase: 0.0,
+            noise_state: 0x2545_F491,
+            noise: 1.0,
+            pattern: None,
+            pattern_inc: 0.0,
+            pattern_pos: 0.0,
+            sample_rate,
+            remaining: 0,
+            gain: 0.0,
+            gain_step: 1. / (ENVELOPE_SECONDS * sample_rate),
+        }
+    }
+
+    pub fn tone(&self) -> Tone {
+        self.tone
+    }
+
+    /// Change the tone, see `Tone::clamped`.
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone.clamped();
+        self.phase_inc = self.tone.frequency / self.sample_rate;
+    }
+
+    /// Play an XO-CHIP audio pattern at `pitch`, `None` plays the tone.
+    pub fn set_pattern(&mut self, pattern: Option<&[u8; AUDIO_PATTERN_SIZE]>, pitch: u8) {
+        self.pattern = pattern.copied();
+        self.pattern_inc = pattern_rate(pitch) / self.sample_rate;
+    }
+
+    /// Sound for `sound_timer` frames from now, or stop if it's 0. This is
+    /// called every frame, so a program holding the timer at the same value
+    /// keeps the tone going. The wave keeps its phase, so back to back beeps
+    /// join up without a click.
+    pub fn set_sound_timer(&mut self, sound_timer: u8) {
+        self.remaining = (sound_timer as f32 * self.sample_rate / TARGET_FPS as f32) as u32;
+    }
+
+    /// Follow the sound state of `machine` after it has run a frame, staying
+    /// quiet while `paused`.
+    pub fn update(&mut self, machine: &Machine, paused: bool) {
+        self.set_pattern(machine.audio_pattern(), machine.pitch());
+        self.set_sound_timer(if paused { 0 } else { machine.sound_timer() });
+    }
+
+    /// Move the envelope a sample towards full volume while the sound timer
+    /// is running and silence once it stops.
+    fn envelope(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.gain = (self.gain + self.gain_step).min(1.);
+        } else {
+            self.gain = (self.gain - self.gain_step).max(0.);
+        }
+        self.gain
+    }
+
+    fn next_noise(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        x as f32 / u32::MAX as f32 * 2. - 1.
+    }
+
+    /// The next sample, between -1 and 1.
+    pub fn sample(&mut self) -> f32 {
+        let volume = self.tone.volume * self.envelope();
+        match self.pattern {
+            Some(pattern) => {
+                let bit = self.pattern_pos as usize;
+                let on = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+                self.pattern_pos = (self.pattern_pos + self.pattern_inc) % PATTERN_BITS;
+                if on {
+                    volume
+                } else {
+                    -volume
+                }
+            }
+            None => {
+                let x = self.tone.waveform.level(self.phase, self.noise);
+                self.phase += self.phase_inc;
+                if self.phase >= 1.0 {
+                    self.phase %= 1.0;
+                    self.noise = self.next_noise();
+                }
+                volume * x
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        let levels = |w: Waveform| [0., 0.25, 0.5, 0.75].map(|phase| w.level(phase, 0.5));
+        assert_eq!(levels(Waveform::Square), [1., 1., 1., -1.]);
+        assert_eq!(levels(Waveform::Triangle), [-1., 0., 1., 0.]);
+        assert_eq!(levels(Waveform::Sawtooth), [-1., -0.5, 0., 0.5]);
+        assert_eq!(levels(Waveform::Noise), [0.5; 4]);
+        let sine = levels(Waveform::Sine);
+        assert!(sine[0].abs() < 1e-6 && (sine[1] - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_next() {
+        let mut waveform = Waveform::Square;
+        for _ in 0..Waveform::ALL.len() {
+            waveform = waveform.next();
+        }
+        assert_eq!(waveform, Waveform::Square);
+    }
+
+    #[test]
+    fn test_sample() {
+        let tone = Tone {
+            waveform: Waveform::Noise,
+            frequency: 100.,
+            volume: 0.5,
+        };
+        // ten periods of noise
+        let mut synth = Synth::new(tone, 1000);
+        synth.set_sound_timer(255);
+        let samples: Vec<f32> = (0..100).map(|_| synth.sample()).collect();
+        assert!(samples.iter().all(|x| x.abs() <= 0.5));
+        assert!(samples.iter().any(|x| *x != samples[0]));
+
+        synth.set_tone(Tone {
+            waveform: Waveform::Square,
+            ..tone
+        });
+        synth.phase = 0.;
+        assert_eq!(synth.sample(), 0.5);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let tone = Tone {
+            waveform: Waveform::Square,
+            frequency: 100.,
+            volume: 1.,
+        };
+        // a frame is 100 samples and the envelope 12
+        let mut synth = Synth::new(tone, 6000);
+        let samples =
+            |synth: &mut Synth, n| -> Vec<f32> { (0..n).map(|_| synth.sample()).collect() };
+        assert!(samples(&mut synth, 10).iter().all(|x| *x == 0.));
+
+        synth.set_sound_timer(2);
+        let beep = samples(&mut synth, 300);
+        // fades in rather than jumping to full volume
+        assert!(beep[0] > 0. && beep[0] < 0.1);
+        // the square wave flips sign, but its level only changes gradually
+        assert!(beep
+            .windows(2)
+            .all(|w| (w[1].abs() - w[0].abs()).abs() <= 0.1));
+        assert_eq!(beep[100].abs(), 1.);
+        // and fades out once the timer runs out
+        assert!(beep[200].abs() < 1. && beep[220] == 0.);
+
+        synth.set_sound_timer(3);
+        assert_eq!(synth.remaining, 300);
+        synth.set_sound_timer(0);
+        assert_eq!(synth.remaining, 0);
+    }
+
+    #[test]
+    fn test_held_sound_timer() {
+        // a frame is 100 samples
+        let mut synth = Synth::new(Tone::default(), 6000);
+        // a program setting the timer to 2 every frame sounds for longer than
+        // 2 frames
+        for _ in 0..10 {
+            synth.set_sound_timer(2);
+            let frame: Vec<f32> = (0..100).map(|_| synth.sample()).collect();
+            assert!(frame.iter().any(|x| *x != 0.));
+        }
+    }
+
+    #[test]
+    fn test_set_tone_clamps() {
+        let mut synth = Synth::new(Tone::default(), 44100);
+        synth.set_tone(Tone {
+            frequency: 1.,
+            volume: 2.,
+            ..Tone::default()
+        });
+        assert_eq!(synth.tone().frequency, MIN_FREQUENCY);
+        assert_eq!(synth.tone().volume, 1.);
+    }
+}
@@ -1,27 +1,20 @@
+use chip8::audio::{Synth, Tone};
+use chip8::Machine;
 use sdl2::{
     audio::{AudioCallback, AudioDevice, AudioSpecDesired},
     AudioSubsystem,
 };
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
-}
+struct Wave(Synth);
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Wave {
     type Channel = u8;
 
     fn callback(&mut self, output: &mut [u8]) {
         for out in output.iter_mut() {
-            let x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
+            let x = self.0.sample();
             let half_max = u8::MAX as f32 / 2.;
             *out = (half_max * x + half_max) as u8;
-            self.phase = (self.phase + self.phase_inc) % 1.0;
         }
     }
 }
@@ -29,27 +22,85 @@ impl AudioCallback for SquareWave {
 const DEFAULT_FREQ: i32 = 44100;
 const DEFAULT_SAMPLES: u16 = 512;
 
-pub struct Buzzer(AudioDevice<SquareWave>);
+pub struct Buzzer {
+    sys: AudioSubsystem,
+    device: AudioDevice<Wave>,
+    /// name of the device playing, `None` for the system's default
+    name: Option<String>,
+}
+
+/// Names of the playback devices SDL knows about.
+fn device_names(sys: &AudioSubsystem) -> Vec<String> {
+    let count = sys.num_audio_playback_devices().unwrap_or(0);
+    (0..count)
+        .filter_map(|i| sys.audio_playback_device_name(i).ok())
+        .collect()
+}
+
+/// Open the playback device called `name`, or the default one, and start it
+/// playing `tone`.
+fn open(sys: &AudioSubsystem, name: Option<&str>, tone: Tone) -> Result<AudioDevice<Wave>, String> {
+    let desired_spec = AudioSpecDesired {
+        freq: Some(DEFAULT_FREQ),
+        samples: Some(DEFAULT_SAMPLES),
+        channels: Some(1),
+    };
+    let device = sys
+        .open_playback(name, &desired_spec, |spec| {
+            Wave(Synth::new(tone, spec.freq as u32))
+        })
+        .map_err(|e| match name {
+            Some(name) => format!(
+                "failed to open audio device {:?}: {}\navailable devices: {}",
+                name,
+                e,
+                device_names(sys).join(", ")
+            ),
+            None => e,
+        })?;
+    // the device plays all the time, silence comes from the envelope
+    device.resume();
+    Ok(device)
+}
 
 impl Buzzer {
-    pub fn new(sys: AudioSubsystem) -> Result<Self, String> {
-        let desired_spec = AudioSpecDesired {
-            freq: Some(DEFAULT_FREQ),
-            samples: Some(DEFAULT_SAMPLES),
-            channels: Some(1),
+    /// Open the playback device called `device`, or the default one.
+    pub fn new(sys: AudioSubsystem, device: Option<&str>, tone: Tone) -> Result<Self, String> {
+        Ok(Self {
+            device: open(&sys, device, tone)?,
+            sys,
+            name: device.map(String::from),
+        })
+    }
+
+    /// Move to the playback device called `name`, or the default one, keeping
+    /// the tone. The current device keeps playing if the new one won't open.
+    pub fn set_device(&mut self, name: Option<&str>) -> Result<(), String> {
+        let tone = self.device.lock().0.tone();
+        self.device = open(&self.sys, name, tone)?;
+        self.name = name.map(String::from);
+        Ok(())
+    }
+
+    /// Move to the next playback device, going back to the default one after
+    /// the last. Returns the name of the device now playing.
+    pub fn next_device(&mut self) -> Result<String, String> {
+        let names = device_names(&self.sys);
+        let next = match &self.name {
+            None => names.first(),
+            Some(name) => names.iter().skip_while(|n| *n != name).nth(1),
         };
-        let device = sys.open_playback(None, &desired_spec, |spec| SquareWave {
-            phase_inc: 150.0 / spec.freq as f32,
-            phase: 0.0,
-            volume: 0.25,
-        })?;
-        Ok(Self(device))
+        self.set_device(next.map(String::as_str))?;
+        Ok(next.cloned().unwrap_or_else(|| "default".to_string()))
     }
 
-    pub fn play(&self) {
-        self.0.resume()
+    /// Change the tone, takes effect immediately even while playing.
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.device.lock().0.set_tone(tone);
     }
-    pub fn pause(&self) {
-        self.0.pause()
+
+    /// Follow the sound state of `machine`, see `Synth::update`.
+    pub fn update(&mut self, machine: &Machine, paused: bool) {
+        self.device.lock().0.update(machine, paused);
     }
 }
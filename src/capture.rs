@@ -0,0 +1,407 @@
+use chip8::audio::{Synth, Tone};
+use chip8::machine::TARGET_FPS;
+use chip8::{snapshot, Machine};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Write RGB pixel data to a PNG file.
+pub fn write_png(path: &Path, rgb: &[u8], (width, height): (usize, usize)) -> Result<(), String> {
+    let context = |e: String| format!("failed to write {}: {}", path.display(), e);
+    let file = File::create(path).map_err(|e| context(e.to_string()))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| context(e.to_string()))?;
+    writer
+        .write_image_data(rgb)
+        .map_err(|e| context(e.to_string()))?;
+    writer.finish().map_err(|e| context(e.to_string()))
+}
+
+/// The first of `GAME.1.ext`, `GAME.2.ext`... next to the ROM that doesn't
+/// exist yet.
+fn free_path(rom_path: &Path, extension: &str) -> PathBuf {
+    (1..)
+        .map(|n| rom_path.with_extension(format!("{}.{}", n, extension)))
+        .find(|path| !path.exists())
+        .expect("ran out of file names")
+}
+
+/// The first of `GAME.1.png`, `GAME.2.png`... next to the ROM that doesn't
+/// exist yet.
+pub fn screenshot_path(rom_path: &Path) -> PathBuf {
+    free_path(rom_path, "png")
+}
+
+/// GIF delays are in hundredths of a second, and browsers slow down anything
+/// shorter than this, so frames closer together than this are merged.
+const GIF_MIN_DELAY: u32 = 2;
+
+enum Video {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        /// the frame waiting for its delay to be known, and when it started
+        pending: Option<(Vec<u8>, u32)>,
+    },
+    Y4m {
+        writer: BufWriter<File>,
+        /// the palette converted to YCbCr
+        palette: [[u8; 3]; 4],
+    },
+}
+
+/// Records frames of the screen to an animated GIF, or a Y4M video if the
+/// path ends in `.y4m`. Every frame is scaled to the size the recording
+/// started with.
+pub struct VideoRecorder {
+    path: PathBuf,
+    video: Video,
+    size: (usize, usize),
+    /// number of frames added so far
+    frames: u32,
+}
+
+/// Convert a colour to YCbCr with the BT.601 coefficients used by Y4M.
+fn to_ycbcr([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let y = 16. + (65.481 * r + 128.553 * g + 24.966 * b) / 255.;
+    let cb = 128. + (-37.797 * r - 74.203 * g + 112. * b) / 255.;
+    let cr = 128. + (112. * r - 93.786 * g - 18.214 * b) / 255.;
+    [y.round() as u8, cb.round() as u8, cr.round() as u8]
+}
+
+impl VideoRecorder {
+    pub fn create(
+        path: &Path,
+        size: (usize, usize),
+        palette: &[[u8; 3]; 4],
+    ) -> Result<Self, String> {
+        let context = |e: String| format!("failed to record to {}: {}", path.display(), e);
+        let file = File::create(path).map_err(|e| context(e.to_string()))?;
+        let mut writer = BufWriter::new(file);
+        let is_y4m = path.extension().is_some_and(|ext| ext == "y4m");
+        let video = if is_y4m {
+            let header = format!(
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444\n",
+                size.0, size.1, TARGET_FPS
+            );
+            writer
+                .write_all(header.as_bytes())
+                .map_err(|e| context(e.to_string()))?;
+            Video::Y4m {
+                writer,
+                palette: palette.map(to_ycbcr),
+            }
+        } else {
+            let mut encoder =
+                gif::Encoder::new(writer, size.0 as u16, size.1 as u16, palette.as_flattened())
+                    .map_err(|e| context(e.to_string()))?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(|e| context(e.to_string()))?;
+            Video::Gif {
+                encoder,
+                pending: None,
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            video,
+            size,
+            frames: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Time since the recording started in hundredths of a second.
+    fn elapsed(&self) -> u32 {
+        self.frames * 100 / TARGET_FPS
+    }
+
+    /// Add a frame, these are expected at `TARGET_FPS`.
+    pub fn add_frame(&mut self, buffer: &[u8], resolution: (usize, usize)) -> Result<(), String> {
+        let pixels = snapshot::scale(buffer, resolution, self.size);
+        let now = self.elapsed();
+        self.frames += 1;
+        let size = self.size;
+        let result = match &mut self.video {
+            Video::Gif { encoder, pending } => match pending.take() {
+                // identical frames just make the pending one last longer
+                Some((prev, start)) if prev == pixels => {
+                    *pending = Some((prev, start));
+                    Ok(())
+                }
+                // too soon to show another frame, replace the pending one
+                Some((_, start)) if now - start < GIF_MIN_DELAY => {
+                    *pending = Some((pixels, start));
+                    Ok(())
+                }
+                Some((prev, start)) => {
+                    *pending = Some((pixels, now));
+                    write_gif_frame(encoder, size, prev, now - start)
+                }
+                None => {
+                    *pending = Some((pixels, now));
+                    Ok(())
+                }
+            },
+            Video::Y4m { writer, palette } => {
+                let mut frame = Vec::with_capacity(6 + pixels.len() * 3);
+                frame.extend_from_slice(b"FRAME\n");
+                let colours: Vec<[u8; 3]> =
+                    pixels.iter().map(|p| palette[*p as usize & 0b11]).collect();
+                // Y, Cb and Cr are stored one after the other
+                for plane in 0..3 {
+                    frame.extend(colours.iter().map(|c| c[plane]));
+                }
+                writer.write_all(&frame).map_err(|e| e.to_string())
+            }
+        };
+        result.map_err(|e| format!("failed to record to {}: {}", self.path.display(), e))
+    }
+
+    /// Write out anything buffered and close the file.
+    pub fn finish(self) -> Result<(), String> {
+        let end = self.elapsed();
+        let result = match self.video {
+            Video::Gif {
+                mut encoder,
+                pending,
+            } => {
+                let result = match pending {
+                    Some((prev, start)) => {
+                        write_gif_frame(&mut encoder, self.size, prev, end - start)
+                    }
+                    None => Ok(()),
+                };
+                result.and_then(|_| {
+                    let mut writer = encoder.into_inner().map_err(|e| e.to_string())?;
+                    writer.flush().map_err(|e| e.to_string())
+                })
+            }
+            Video::Y4m { mut writer, .. } => writer.flush().map_err(|e| e.to_string()),
+        };
+        result.map_err(|e| format!("failed to record to {}: {}", self.path.display(), e))
+    }
+}
+
+fn write_gif_frame(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    (width, height): (usize, usize),
+    pixels: Vec<u8>,
+    delay: u32,
+) -> Result<(), String> {
+    let frame = gif::Frame {
+        width: width as u16,
+        height: height as u16,
+        buffer: pixels.into(),
+        delay: delay.max(GIF_MIN_DELAY) as u16,
+        ..Default::default()
+    };
+    encoder.write_frame(&frame).map_err(|e| e.to_string())
+}
+
+/// The first of `GAME.1.gif`, `GAME.2.gif`... next to the ROM that doesn't
+/// exist yet.
+pub fn recording_path(rom_path: &Path) -> PathBuf {
+    free_path(rom_path, "gif")
+}
+
+/// Sample rate of audio recordings, a whole number of samples per frame.
+const AUDIO_SAMPLE_RATE: u32 = 44100;
+/// size of the WAV header before the samples
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// Records what the buzzer plays to a 16-bit mono WAV file. Sound is rendered
+/// from the emulated frames rather than captured from a device, so it keeps
+/// in step with the emulation and works without a sound card.
+pub struct AudioRecorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    synth: Synth,
+    /// number of frames added so far
+    frames: u64,
+}
+
+impl AudioRecorder {
+    pub fn create(path: &Path, tone: Tone) -> Result<Self, String> {
+        let context = |e: String| format!("failed to record audio to {}: {}", path.display(), e);
+        let file = File::create(path).map_err(|e| context(e.to_string()))?;
+        let mut writer = BufWriter::new(file);
+        // the sizes are filled in by finish
+        writer
+            .write_all(&wav_header(0))
+            .map_err(|e| context(e.to_string()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+            synth: Synth::new(tone, AUDIO_SAMPLE_RATE),
+            frames: 0,
+        })
+    }
+
+    /// Change the tone from the next frame on.
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.synth.set_tone(tone);
+    }
+
+    /// Add the sound of a frame `machine` has just run, or silence if
+    /// `paused`. These are expected at `TARGET_FPS`.
+    pub fn add_frame(&mut self, machine: &Machine, paused: bool) -> Result<(), String> {
+        self.synth.update(machine, paused);
+        let fps = TARGET_FPS as u64;
+        let start = self.frames * AUDIO_SAMPLE_RATE as u64 / fps;
+        self.frames += 1;
+        let end = self.frames * AUDIO_SAMPLE_RATE as u64 / fps;
+        let samples: Vec<u8> = (start..end)
+            .flat_map(|_| ((self.synth.sample() * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+        self.writer
+            .write_all(&samples)
+            .map_err(|e| format!("failed to record audio to {}: {}", self.path.display(), e))
+    }
+
+    /// Fill in the sizes in the header and close the file.
+    pub fn finish(mut self) -> Result<(), String> {
+        let result = (|| {
+            let data_size = self.writer.stream_position()? as u32 - WAV_HEADER_SIZE;
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.writer.write_all(&wav_header(data_size))?;
+            self.writer.flush()
+        })();
+        result.map_err(|e| format!("failed to record audio to {}: {}", self.path.display(), e))
+    }
+}
+
+/// The header of a 16-bit mono PCM WAV file with `data_size` bytes of samples.
+fn wav_header(data_size: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, 1 channel
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&AUDIO_SAMPLE_RATE.to_le_bytes());
+    // bytes per second, bytes per sample and bits per sample
+    header.extend_from_slice(&(AUDIO_SAMPLE_RATE * 2).to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTE: [[u8; 3]; 4] = [[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 0, 255]];
+
+    #[test]
+    fn test_to_ycbcr() {
+        assert_eq!(to_ycbcr([0, 0, 0]), [16, 128, 128]);
+        assert_eq!(to_ycbcr([255, 255, 255]), [235, 128, 128]);
+    }
+
+    #[test]
+    fn test_y4m() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.y4m", std::process::id()));
+        let mut recorder = VideoRecorder::create(&path, (4, 2), &PALETTE).unwrap();
+        recorder.add_frame(&[0, 1], (2, 1)).unwrap();
+        recorder.add_frame(&[1, 0], (2, 1)).unwrap();
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = b"YUV4MPEG2 W4 H2 F60:1 Ip A1:1 C444\n";
+        assert!(data.starts_with(header));
+        // each frame has a marker and three full size planes
+        assert_eq!(data.len(), header.len() + 2 * (6 + 4 * 2 * 3));
+        assert_eq!(
+            &data[header.len()..header.len() + 10],
+            b"FRAME\n\x10\x10\xEB\xEB"
+        );
+    }
+
+    #[test]
+    fn test_gif() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.gif", std::process::id()));
+        let mut recorder = VideoRecorder::create(&path, (2, 1), &PALETTE).unwrap();
+        for frame in 0..10 {
+            let pixels = if frame < 5 { [0, 1] } else { [1, 0] };
+            recorder.add_frame(&pixels, (2, 1)).unwrap();
+        }
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(data.starts_with(b"GIF89a"));
+        // two frames, the repeated ones are merged
+        let frames = data.windows(2).filter(|w| w == &[0x21, 0xF9]).count();
+        assert_eq!(frames, 2);
+    }
+
+    #[test]
+    fn test_wav() {
+        // 6005: LD V0, 0x05
+        // F018: LD ST, V0
+        // 1204: JP 0x204
+        let rom = [0x60, 0x05, 0xF0, 0x18, 0x12, 0x04];
+        let mut machine = Machine::new(&rom, chip8::Quirks::default()).unwrap();
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.wav", std::process::id()));
+        let mut recorder = AudioRecorder::create(&path, Tone::default()).unwrap();
+        for _ in 0..10 {
+            machine.run_frame().unwrap();
+            recorder.add_frame(&machine, false).unwrap();
+        }
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let samples_per_frame = (AUDIO_SAMPLE_RATE / TARGET_FPS) as usize;
+        assert_eq!(
+            data[..44],
+            wav_header(10 * samples_per_frame as u32 * 2)[..]
+        );
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        // the timer is set to 5 in the first frame, so it sounds for 5 frames
+        let beep = 5 * samples_per_frame;
+        assert!(samples[..beep].iter().any(|x| *x != 0));
+        assert!(samples[beep + 100..].iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn test_wav_held_sound_timer() {
+        // 6002: LD V0, 0x02
+        // F018: LD ST, V0
+        // 1202: JP 0x202
+        let rom = [0x60, 0x02, 0xF0, 0x18, 0x12, 0x02];
+        let mut machine = Machine::new(&rom, chip8::Quirks::default()).unwrap();
+        let path = std::env::temp_dir().join(format!("chip8-test-held-{}.wav", std::process::id()));
+        let mut recorder = AudioRecorder::create(&path, Tone::default()).unwrap();
+        for _ in 0..10 {
+            machine.run_frame().unwrap();
+            recorder.add_frame(&machine, false).unwrap();
+        }
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the timer is set back to 2 every frame, so the tone never stops
+        let samples_per_frame = (AUDIO_SAMPLE_RATE / TARGET_FPS) as usize;
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        for frame in samples.chunks(samples_per_frame) {
+            assert!(frame.iter().any(|x| *x != 0));
+        }
+    }
+}
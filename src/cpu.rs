@@ -1,7 +1,11 @@
-use crate::memory::Memory;
+use crate::fonts;
+use crate::memory::{self, Memory, OutOfBounds};
+use crate::quirks::Quirks;
+use crate::rng::Rng;
 use crate::screen::Screen;
+use crate::state::{Reader, StateError, Writer};
 
-use rand::Rng;
+use std::fmt;
 
 fn u8_from_nibbles(n1: u8, n2: u8) -> u8 {
     (n1 << 4) | n2
@@ -21,17 +25,6 @@ fn test_u16_from_nibbles() {
     assert_eq!(0xABCD, u16_from_nibbles(0xA, 0xB, 0xC, 0xD));
 }
 
-fn u8_to_nibbles(i: u8) -> (u8, u8) {
-    ((0xF0 & i) >> 4, 0x0F & i)
-}
-
-#[test]
-fn test_u8_to_nibbles() {
-    let (n1, n2) = u8_to_nibbles(0xAB);
-    assert_eq!(n1, 0xA);
-    assert_eq!(n2, 0xB);
-}
-
 fn u16_to_nibbles(i: u16) -> (u8, u8, u8, u8) {
     (
         ((0xF000 & i) >> 12) as u8,
@@ -50,10 +43,58 @@ fn test_u16_to_nibbles() {
     assert_eq!(n4, 0xD);
 }
 
+/// The registers from `x` to `y` inclusive, in reverse when `x` > `y`.
+fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = u8>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
+}
+
 const NUM_REGS: usize = 16;
 const NUM_KEYS: usize = 16;
+/// maximum number of nested subroutine calls
+const STACK_DEPTH: usize = 16;
+/// size of the XO-CHIP audio pattern buffer in bytes
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+/// XO-CHIP pitch register value that plays the pattern at 4000 Hz
+const DEFAULT_PITCH: u8 = 64;
+/// XO-CHIP `F000 NNNN` opcode, which is followed by a 16 bit address
+pub const LONG_INDEX_OPCODE: u16 = 0xF000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuError {
+    InvalidOpcode { pc: u16, opcode: u16 },
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfBounds { addr: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode 0x{:04X} at 0x{:04X}", opcode, pc)
+            }
+            CpuError::StackOverflow => write!(f, "stack overflow"),
+            CpuError::StackUnderflow => write!(f, "stack underflow"),
+            CpuError::MemoryOutOfBounds { addr } => {
+                write!(f, "memory access out of bounds at 0x{:04X}", addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
 
-#[derive(Debug)]
+impl From<OutOfBounds> for CpuError {
+    fn from(OutOfBounds(addr): OutOfBounds) -> Self {
+        CpuError::MemoryOutOfBounds { addr }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct CPU {
     // TODO: consider type alias for register
     /// V registers
@@ -62,8 +103,10 @@ pub struct CPU {
     i: u16,
     /// program counter
     pc: u16,
-    /// stack pointer
+    /// stack pointer, the number of return addresses on the stack
     sp: u16,
+    /// return addresses of subroutine calls
+    stack: [u16; STACK_DEPTH],
     /// delay timer
     dt: u8,
     /// sound timer
@@ -74,18 +117,44 @@ pub struct CPU {
     memory: Memory,
     /// screen buffer
     screen: Screen,
+    /// interpreter specific behaviour
+    quirks: Quirks,
+    /// whether a draw is allowed this frame, see `Quirks::display_wait`
+    vblank: bool,
+    /// SUPER-CHIP RPL user flags
+    flags: [u8; NUM_REGS],
+    /// set once the program has exited with 00FD
+    exited: bool,
+    /// XO-CHIP audio pattern, `None` until a program loads one
+    audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    /// XO-CHIP audio pitch register
+    pitch: u8,
+    /// source of CXNN random numbers
+    rng: Rng,
 }
 
-#[derive(Debug)]
-enum Instruction {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
     Clear,
     Return,
+    Exit,
+    Lores,
+    Hires,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
     Set(u8, u8),
     SetIndex(u16),
+    SetLongIndex,
+    SelectPlanes(u8),
+    LoadAudio,
+    SetPitch(u8),
     SetDelay(u8),
     SetSound(u8),
     SetVxToVy(u8, u8),
     SetFont(u8),
+    SetLargeFont(u8),
     ReadDelay(u8),
     Random(u8, u8),
     Add(u8, u8),
@@ -111,90 +180,297 @@ enum Instruction {
     Draw(u8, u8, u8),
     Load(u8),
     Store(u8),
+    LoadFlags(u8),
+    StoreFlags(u8),
+    LoadRange(u8, u8),
+    StoreRange(u8, u8),
     WaitUntilPressed(u8),
 }
 
+/// Decode an opcode, returns `None` for opcodes that aren't instructions.
+pub fn decode(opcode: u16) -> Option<Instruction> {
+    use Instruction::*;
+    let inst = match u16_to_nibbles(opcode) {
+        (0x0, 0x0, 0xE, 0x0) => Clear,
+        (0x0, 0x0, 0xE, 0xE) => Return,
+        (0x0, 0x0, 0xC, n) => ScrollDown(n),
+        (0x0, 0x0, 0xD, n) => ScrollUp(n),
+        (0x0, 0x0, 0xF, 0xB) => ScrollRight,
+        (0x0, 0x0, 0xF, 0xC) => ScrollLeft,
+        (0x0, 0x0, 0xF, 0xD) => Exit,
+        (0x0, 0x0, 0xF, 0xE) => Lores,
+        (0x0, 0x0, 0xF, 0xF) => Hires,
+        (0x1, n1, n2, n3) => Jump(u16_from_nibbles(0x0, n1, n2, n3)),
+        (0x2, n1, n2, n3) => Call(u16_from_nibbles(0x0, n1, n2, n3)),
+        (0x3, x, n1, n2) => SkipIfEq(x, u8_from_nibbles(n1, n2)),
+        (0x4, x, n1, n2) => SkipIfNe(x, u8_from_nibbles(n1, n2)),
+        (0x5, x, y, 0x0) => SkipIfVxVyEq(x, y),
+        (0x5, x, y, 0x2) => StoreRange(x, y),
+        (0x5, x, y, 0x3) => LoadRange(x, y),
+        (0x6, x, n1, n2) => Set(x, u8_from_nibbles(n1, n2)),
+        (0x7, x, n1, n2) => Add(x, u8_from_nibbles(n1, n2)),
+        (0x8, x, y, 0x0) => SetVxToVy(x, y),
+        (0x8, x, y, 0x1) => BinaryOr(x, y),
+        (0x8, x, y, 0x2) => BinaryAnd(x, y),
+        (0x8, x, y, 0x3) => BinaryXor(x, y),
+        (0x8, x, y, 0x4) => AddVxToVy(x, y),
+        (0x8, x, y, 0x5) => SubtractVyFromVx(x, y),
+        (0x8, x, y, 0x6) => ShiftRight(x, y),
+        (0x8, x, y, 0x7) => SubtractVxFromVy(x, y),
+        (0x8, x, y, 0xE) => ShiftLeft(x, y),
+        (0x9, x, y, 0x0) => SkipIfVxVyNe(x, y),
+        (0xA, n1, n2, n3) => SetIndex(u16_from_nibbles(0x0, n1, n2, n3)),
+        (0xB, n1, n2, n3) => JumpV0(u16_from_nibbles(0x0, n1, n2, n3)),
+        (0xC, x, n1, n2) => Random(x, u8_from_nibbles(n1, n2)),
+        (0xD, x, y, n) => Draw(x, y, n),
+        (0xE, x, 0xA, 0x1) => SkipIfNotPressed(x),
+        (0xE, x, 0x9, 0xE) => SkipIfPressed(x),
+        (0xF, 0x0, 0x0, 0x0) => SetLongIndex,
+        (0xF, n, 0x0, 0x1) => SelectPlanes(n),
+        (0xF, 0x0, 0x0, 0x2) => LoadAudio,
+        (0xF, x, 0x0, 0x7) => ReadDelay(x),
+        (0xF, x, 0x0, 0xA) => WaitUntilPressed(x),
+        (0xF, x, 0x1, 0x5) => SetDelay(x),
+        (0xF, x, 0x1, 0x8) => SetSound(x),
+        (0xF, x, 0x1, 0xE) => AddToIndex(x),
+        (0xF, x, 0x2, 0x9) => SetFont(x),
+        (0xF, x, 0x3, 0x0) => SetLargeFont(x),
+        (0xF, x, 0x3, 0x3) => Bcd(x),
+        (0xF, x, 0x3, 0xA) => SetPitch(x),
+        (0xF, x, 0x5, 0x5) => Store(x),
+        (0xF, x, 0x6, 0x5) => Load(x),
+        (0xF, x, 0x7, 0x5) => StoreFlags(x),
+        (0xF, x, 0x8, 0x5) => LoadFlags(x),
+        _ => return None,
+    };
+    Some(inst)
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Clear => write!(f, "CLS"),
+            Return => write!(f, "RET"),
+            Exit => write!(f, "EXIT"),
+            Lores => write!(f, "LOW"),
+            Hires => write!(f, "HIGH"),
+            ScrollDown(n) => write!(f, "SCD {}", n),
+            ScrollUp(n) => write!(f, "SCU {}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Set(x, val) => write!(f, "LD V{:X}, 0x{:02X}", x, val),
+            SetIndex(addr) => write!(f, "LD I, 0x{:03X}", addr),
+            SetLongIndex => write!(f, "LD I, LONG"),
+            SelectPlanes(n) => write!(f, "PLANE {}", n),
+            LoadAudio => write!(f, "AUDIO"),
+            SetPitch(x) => write!(f, "PITCH V{:X}", x),
+            SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            SetVxToVy(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            SetFont(x) => write!(f, "LD F, V{:X}", x),
+            SetLargeFont(x) => write!(f, "LD HF, V{:X}", x),
+            ReadDelay(x) => write!(f, "LD V{:X}, DT", x),
+            Random(x, val) => write!(f, "RND V{:X}, 0x{:02X}", x, val),
+            Add(x, val) => write!(f, "ADD V{:X}, 0x{:02X}", x, val),
+            AddToIndex(x) => write!(f, "ADD I, V{:X}", x),
+            AddVxToVy(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            SubtractVyFromVx(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            SubtractVxFromVy(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            BinaryOr(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            BinaryAnd(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            BinaryXor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Jump(addr) => write!(f, "JP 0x{:03X}", addr),
+            JumpV0(addr) => write!(f, "JP V0, 0x{:03X}", addr),
+            Call(addr) => write!(f, "CALL 0x{:03X}", addr),
+            SkipIfEq(x, val) => write!(f, "SE V{:X}, 0x{:02X}", x, val),
+            SkipIfNe(x, val) => write!(f, "SNE V{:X}, 0x{:02X}", x, val),
+            SkipIfVxVyEq(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            SkipIfVxVyNe(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            SkipIfPressed(x) => write!(f, "SKP V{:X}", x),
+            SkipIfNotPressed(x) => write!(f, "SKNP V{:X}", x),
+            Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Store(x) => write!(f, "LD [I], V{:X}", x),
+            LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            LoadRange(x, y) => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            StoreRange(x, y) => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            WaitUntilPressed(x) => write!(f, "LD V{:X}, K", x),
+        }
+    }
+}
+
 impl CPU {
-    pub fn new(memory: Memory) -> Self {
+    /// Create a CPU running the program in `memory`, CXNN draws its random
+    /// numbers from `rng`.
+    pub fn new(memory: Memory, quirks: Quirks, rng: Rng) -> Self {
         Self {
             v: [0; NUM_REGS],
             i: 0,
             pc: 0x200,
-            sp: 0xFA0,
+            sp: 0,
+            stack: [0; STACK_DEPTH],
             dt: 0,
             st: 0,
             keys: [false; NUM_KEYS],
             memory,
             screen: Screen::new(),
+            quirks,
+            vblank: true,
+            flags: [0; NUM_REGS],
+            exited: false,
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
+            rng,
         }
     }
 
+    /// Put the CPU back how `new` left it, clearing the registers, timers and
+    /// screen. Memory is kept as it is, along with the keys held down, the
+    /// RPL user flags and the random number generator.
+    pub fn reset(&mut self) {
+        let memory = std::mem::take(&mut self.memory);
+        let rng = std::mem::replace(&mut self.rng, Rng::Thread);
+        *self = Self {
+            keys: self.keys,
+            flags: self.flags,
+            ..Self::new(memory, self.quirks, rng)
+        };
+    }
+
     pub fn press_key(&mut self, key: u8, pressed: bool) {
         assert!(key < 0x10);
         self.keys[key as usize] = pressed;
     }
 
-    pub fn screen_buffer(&self) -> &[bool] {
+    /// V registers
+    pub fn v(&self) -> &[u8; NUM_REGS] {
+        &self.v
+    }
+
+    /// I register
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    /// program counter
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// stack pointer
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    /// delay timer
+    pub fn dt(&self) -> u8 {
+        self.dt
+    }
+
+    /// sound timer
+    pub fn st(&self) -> u8 {
+        self.st
+    }
+
+    /// currently pressed keys
+    pub fn keys(&self) -> &[bool; NUM_KEYS] {
+        &self.keys
+    }
+
+    /// return addresses currently on the stack, the most recent call is last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn screen_buffer(&self) -> &[u8] {
         self.screen.buffer()
     }
 
+    /// The current screen resolution as (width, height).
+    pub fn screen_resolution(&self) -> (usize, usize) {
+        (self.screen.width(), self.screen.height())
+    }
+
+    /// The XO-CHIP audio pattern, `None` if the program hasn't loaded one.
+    pub fn audio_pattern(&self) -> Option<&[u8; AUDIO_PATTERN_SIZE]> {
+        self.audio_pattern.as_ref()
+    }
+
+    /// The XO-CHIP audio pitch register.
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    /// The interpreter behaviour being emulated.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Whether the program has exited, the CPU does nothing once exited.
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
     pub fn is_sound_playing(&self) -> bool {
-        self.st > 2
+        self.st > 0
     }
 
-    fn fetch(&mut self) -> u16 {
-        let opcode = self.memory.read_u16(self.pc);
-        self.pc += 2;
-        opcode
+    fn fetch(&mut self) -> Result<u16, CpuError> {
+        let opcode = self.memory.read_u16(self.pc)?;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(opcode)
     }
 
-    fn decode(&self, opcode: u16) -> Instruction {
-        use Instruction::*;
-        match u16_to_nibbles(opcode) {
-            (0x0, 0x0, 0xE, 0x0) => Clear,
-            (0x0, 0x0, 0xE, 0xE) => Return,
-            (0x1, n1, n2, n3) => Jump(u16_from_nibbles(0x0, n1, n2, n3)),
-            (0x2, n1, n2, n3) => Call(u16_from_nibbles(0x0, n1, n2, n3)),
-            (0x3, x, n1, n2) => SkipIfEq(x, u8_from_nibbles(n1, n2)),
-            (0x4, x, n1, n2) => SkipIfNe(x, u8_from_nibbles(n1, n2)),
-            (0x5, x, y, 0x0) => SkipIfVxVyEq(x, y),
-            (0x6, x, n1, n2) => Set(x, u8_from_nibbles(n1, n2)),
-            (0x7, x, n1, n2) => Add(x, u8_from_nibbles(n1, n2)),
-            (0x8, x, y, 0x0) => SetVxToVy(x, y),
-            (0x8, x, y, 0x1) => BinaryOr(x, y),
-            (0x8, x, y, 0x2) => BinaryAnd(x, y),
-            (0x8, x, y, 0x3) => BinaryXor(x, y),
-            (0x8, x, y, 0x4) => AddVxToVy(x, y),
-            (0x8, x, y, 0x5) => SubtractVyFromVx(x, y),
-            (0x8, x, y, 0x6) => ShiftRight(x, y),
-            (0x8, x, y, 0x7) => SubtractVxFromVy(x, y),
-            (0x8, x, y, 0xE) => ShiftLeft(x, y),
-            (0x9, x, y, 0x0) => SkipIfVxVyNe(x, y),
-            (0xA, n1, n2, n3) => SetIndex(u16_from_nibbles(0x0, n1, n2, n3)),
-            (0xB, n1, n2, n3) => JumpV0(u16_from_nibbles(0x0, n1, n2, n3)),
-            (0xC, x, n1, n2) => Random(x, u8_from_nibbles(n1, n2)),
-            (0xD, x, y, n) => Draw(x, y, n),
-            (0xE, x, 0xA, 0x1) => SkipIfNotPressed(x),
-            (0xE, x, 0x9, 0xE) => SkipIfPressed(x),
-            (0xF, x, 0x0, 0x7) => ReadDelay(x),
-            (0xF, x, 0x0, 0xA) => WaitUntilPressed(x),
-            (0xF, x, 0x1, 0x5) => SetDelay(x),
-            (0xF, x, 0x1, 0x8) => SetSound(x),
-            (0xF, x, 0x1, 0xE) => AddToIndex(x),
-            (0xF, x, 0x2, 0x9) => SetFont(x),
-            (0xF, x, 0x3, 0x3) => Bcd(x),
-            (0xF, x, 0x5, 0x5) => Store(x),
-            (0xF, x, 0x6, 0x5) => Load(x),
-            _ => panic!("Invalid opcode: 0x{:04X}", opcode),
-        }
+    /// Skip the next instruction, accounting for the 4 byte `F000 NNNN`.
+    fn skip(&mut self) -> Result<(), CpuError> {
+        let len = if self.memory.read_u16(self.pc)? == LONG_INDEX_OPCODE {
+            4
+        } else {
+            2
+        };
+        self.pc = self.pc.wrapping_add(len);
+        Ok(())
+    }
+
+    fn decode(&self, opcode: u16) -> Result<Instruction, CpuError> {
+        decode(opcode).ok_or(CpuError::InvalidOpcode {
+            // fetch has already moved past the opcode
+            pc: self.pc.wrapping_sub(2),
+            opcode,
+        })
     }
 
-    fn execute(&mut self, inst: Instruction) {
+    fn execute(&mut self, inst: Instruction) -> Result<(), CpuError> {
         use Instruction::*;
         match inst {
             Clear => self.screen.clear(),
+            Exit => self.exited = true,
+            Lores => self.screen.set_hires(false),
+            Hires => self.screen.set_hires(true),
+            ScrollDown(n) => self.screen.scroll_down(n as usize),
+            ScrollUp(n) => self.screen.scroll_up(n as usize),
+            ScrollRight => self.screen.scroll_right(4),
+            ScrollLeft => self.screen.scroll_left(4),
             Set(vx, val) => self.v[vx as usize] = val,
             SetIndex(val) => self.i = val,
+            SetLongIndex => {
+                self.i = self.memory.read_u16(self.pc)?;
+                self.pc = self.pc.wrapping_add(2);
+            }
+            SelectPlanes(n) => self.screen.select_planes(n),
+            LoadAudio => {
+                let mut pattern = [0; AUDIO_PATTERN_SIZE];
+                for (n, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.memory.read_u8(memory::offset(self.i, n as u16)?)?;
+                }
+                self.audio_pattern = Some(pattern);
+            }
+            SetPitch(vx) => self.pitch = self.v[vx as usize],
             SetDelay(vx) => self.dt = self.v[vx as usize],
             SetSound(vx) => self.st = self.v[vx as usize],
             SetVxToVy(vx, vy) => self.v[vx as usize] = self.v[vy as usize],
@@ -202,9 +478,13 @@ impl CPU {
                 let x = self.v[vx as usize];
                 self.i = x as u16 * 5;
             }
+            SetLargeFont(vx) => {
+                let x = self.v[vx as usize] & 0xF;
+                self.i = fonts::LARGE_BASE_ADDRESS + x as u16 * 10;
+            }
             ReadDelay(vx) => self.v[vx as usize] = self.dt,
             Random(vx, val) => {
-                let rand = rand::thread_rng().gen_range(0..=0xFF);
+                let rand = self.rng.next_u8();
                 self.v[vx as usize] = rand & val;
             }
             Add(vx, val) => {
@@ -212,9 +492,12 @@ impl CPU {
                 self.v[vx as usize] = x;
             }
             AddToIndex(vx) => {
-                self.i += self.v[vx as usize] as u16;
-                if self.i >= 0x1000 {
-                    self.v[0xF] = 1;
+                let x = self.v[vx as usize] as u16;
+                let overflow = (self.i & 0xFFF) + x > 0xFFF;
+                self.i = self.i.wrapping_add(x);
+                // there's nothing to overflow past with 64 KiB of memory
+                if self.quirks.index_overflow && !self.quirks.extended_memory {
+                    self.v[0xF] = overflow as u8;
                 }
             }
             AddVxToVy(vx, vy) => {
@@ -239,31 +522,39 @@ impl CPU {
                 self.v[0xF] = !overflow as u8;
             }
             ShiftRight(vx, vy) => {
-                let y = self.v[vy as usize];
+                let src = if self.quirks.shift { vx } else { vy };
+                let y = self.v[src as usize];
                 self.v[vx as usize] = y >> 1;
                 self.v[0xF] = y & 1;
             }
             ShiftLeft(vx, vy) => {
-                let y = self.v[vy as usize];
+                let src = if self.quirks.shift { vx } else { vy };
+                let y = self.v[src as usize];
                 self.v[vx as usize] = y << 1;
                 self.v[0xF] = y >> 7;
             }
             BinaryOr(vx, vy) => {
                 let x = self.v[vx as usize];
                 let y = self.v[vy as usize];
-                self.v[0xF] = 0;
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
                 self.v[vx as usize] = x | y;
             }
             BinaryAnd(vx, vy) => {
                 let x = self.v[vx as usize];
                 let y = self.v[vy as usize];
-                self.v[0xF] = 0;
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
                 self.v[vx as usize] = x & y;
             }
             BinaryXor(vx, vy) => {
                 let x = self.v[vx as usize];
                 let y = self.v[vy as usize];
-                self.v[0xF] = 0;
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
                 self.v[vx as usize] = x ^ y;
             }
             Bcd(vx) => {
@@ -271,92 +562,195 @@ impl CPU {
                 let ones = x % 10;
                 let tens = (x / 10) % 10;
                 let hundreds = (x / 100) % 10;
-                self.memory.write_u8(self.i, hundreds);
-                self.memory.write_u8(self.i + 1, tens);
-                self.memory.write_u8(self.i + 2, ones);
+                self.memory.write_u8(self.i, hundreds)?;
+                self.memory.write_u8(memory::offset(self.i, 1)?, tens)?;
+                self.memory.write_u8(memory::offset(self.i, 2)?, ones)?;
             }
             Draw(vx, vy, rows) => {
+                if self.quirks.display_wait {
+                    if !self.vblank {
+                        // retry until the next frame
+                        self.pc = self.pc.wrapping_sub(2);
+                        return Ok(());
+                    }
+                    self.vblank = false;
+                }
                 let x = self.v[vx as usize];
                 let y = self.v[vy as usize];
-                let f_flag = self.screen.draw(&self.memory, self.i, x, y, rows);
+                let clip = self.quirks.clipping;
+                let f_flag = self.screen.draw(&self.memory, self.i, x, y, rows, clip)?;
                 self.v[0xF] = f_flag as u8;
             }
             SkipIfEq(vx, val) => {
                 if self.v[vx as usize] == val {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             SkipIfNe(vx, val) => {
                 if self.v[vx as usize] != val {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             SkipIfVxVyEq(vx, vy) => {
                 if self.v[vx as usize] == self.v[vy as usize] {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             SkipIfVxVyNe(vx, vy) => {
                 if self.v[vx as usize] != self.v[vy as usize] {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             SkipIfPressed(vx) => {
-                let x = self.v[vx as usize];
+                let x = self.v[vx as usize] & 0xF;
                 if self.keys[x as usize] {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             SkipIfNotPressed(vx) => {
-                let x = self.v[vx as usize];
+                let x = self.v[vx as usize] & 0xF;
                 if !self.keys[x as usize] {
-                    self.pc += 2;
+                    self.skip()?;
                 }
             }
             Jump(addr) => self.pc = addr,
-            JumpV0(addr) => self.pc = addr + (self.v[0x0] as u16),
+            JumpV0(addr) => {
+                let vx = if self.quirks.jump {
+                    (addr >> 8) & 0xF
+                } else {
+                    0x0
+                };
+                self.pc = addr + (self.v[vx as usize] as u16);
+            }
             Call(addr) => {
-                self.sp += 2;
-                self.memory.write_u16(self.sp, self.pc);
+                let slot = self
+                    .stack
+                    .get_mut(self.sp as usize)
+                    .ok_or(CpuError::StackOverflow)?;
+                *slot = self.pc;
+                self.sp += 1;
                 self.pc = addr;
             }
             Return => {
-                let addr = self.memory.read_u16(self.sp);
-                self.sp -= 2;
-                self.pc = addr;
+                if self.sp == 0 {
+                    return Err(CpuError::StackUnderflow);
+                }
+                self.sp -= 1;
+                self.pc = self.stack[self.sp as usize];
             }
             Load(vx) => {
                 assert!(vx < 0x10);
                 let slice = &mut self.v[0..=vx as usize];
                 for (n, x) in slice.iter_mut().enumerate() {
-                    *x = self.memory.read_u8(self.i + n as u16);
+                    *x = self.memory.read_u8(memory::offset(self.i, n as u16)?)?;
+                }
+                if self.quirks.load_store_increment {
+                    self.i = self.i.wrapping_add(vx as u16 + 1);
                 }
             }
             Store(vx) => {
                 assert!(vx < 0x10);
                 let slice = &self.v[0..=vx as usize];
                 for (n, x) in slice.iter().enumerate() {
-                    let pos = self.i + (n as u16);
-                    self.memory.write_u8(pos, *x);
+                    let pos = memory::offset(self.i, n as u16)?;
+                    self.memory.write_u8(pos, *x)?;
+                }
+                if self.quirks.load_store_increment {
+                    self.i = self.i.wrapping_add(vx as u16 + 1);
+                }
+            }
+            StoreRange(vx, vy) => {
+                for (n, r) in register_range(vx, vy).enumerate() {
+                    let pos = memory::offset(self.i, n as u16)?;
+                    self.memory.write_u8(pos, self.v[r as usize])?;
                 }
             }
+            LoadRange(vx, vy) => {
+                for (n, r) in register_range(vx, vy).enumerate() {
+                    let pos = memory::offset(self.i, n as u16)?;
+                    self.v[r as usize] = self.memory.read_u8(pos)?;
+                }
+            }
+            StoreFlags(vx) => {
+                let n = vx as usize + 1;
+                self.flags[..n].copy_from_slice(&self.v[..n]);
+            }
+            LoadFlags(vx) => {
+                let n = vx as usize + 1;
+                self.v[..n].copy_from_slice(&self.flags[..n]);
+            }
             WaitUntilPressed(vx) => {
                 match self.keys.iter().position(|b| *b) {
                     Some(i) => self.v[vx as usize] = i as u8,
                     // keep looping
-                    None => self.pc -= 2,
+                    None => self.pc = self.pc.wrapping_sub(2),
                 }
             }
         }
+        Ok(())
     }
 
-    pub fn tick(&mut self) {
-        let opcode = self.fetch();
-        let inst = self.decode(opcode);
-        self.execute(inst);
+    /// Write everything except the quirks, which come from the configuration
+    /// rather than the program.
+    pub(crate) fn write_state(&self, w: &mut Writer) {
+        w.bytes(&self.v);
+        w.u16(self.i);
+        w.u16(self.pc);
+        w.u16(self.sp);
+        self.stack.iter().for_each(|addr| w.u16(*addr));
+        w.u8(self.dt);
+        w.u8(self.st);
+        self.keys.iter().for_each(|k| w.bool(*k));
+        w.bytes(&self.flags);
+        w.bool(self.vblank);
+        w.bool(self.exited);
+        w.bool(self.audio_pattern.is_some());
+        w.bytes(&self.audio_pattern.unwrap_or_default());
+        w.u8(self.pitch);
+        self.rng.write_state(w);
+        self.memory.write_state(w);
+        self.screen.write_state(w);
+    }
+
+    pub(crate) fn read_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.v = r.array()?;
+        self.i = r.u16()?;
+        self.pc = r.u16()?;
+        self.sp = r.u16()?;
+        if self.sp as usize > STACK_DEPTH {
+            return Err(StateError::InvalidFormat);
+        }
+        for addr in self.stack.iter_mut() {
+            *addr = r.u16()?;
+        }
+        self.dt = r.u8()?;
+        self.st = r.u8()?;
+        for key in self.keys.iter_mut() {
+            *key = r.bool()?;
+        }
+        self.flags = r.array()?;
+        self.vblank = r.bool()?;
+        self.exited = r.bool()?;
+        let has_pattern = r.bool()?;
+        let pattern = r.array()?;
+        self.audio_pattern = has_pattern.then_some(pattern);
+        self.pitch = r.u8()?;
+        self.rng = Rng::read_state(r)?;
+        self.memory.read_state(r)?;
+        self.screen.read_state(r)
+    }
+
+    pub fn tick(&mut self) -> Result<(), CpuError> {
+        if self.exited {
+            return Ok(());
+        }
+        let opcode = self.fetch()?;
+        let inst = self.decode(opcode)?;
+        self.execute(inst)
     }
 
     pub fn tick_timers(&mut self) {
+        self.vblank = true;
         if self.dt > 0 {
             self.dt -= 1;
         }
@@ -365,3 +759,297 @@ impl CPU {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_with_program(program: &[u8], quirks: Quirks) -> CPU {
+        let mut memory = if quirks.extended_memory {
+            Memory::extended()
+        } else {
+            Memory::new()
+        };
+        memory.load(program, 0x200).unwrap();
+        CPU::new(memory, quirks, Rng::seeded(0))
+    }
+
+    #[test]
+    fn test_shift_quirk() {
+        // 8016: SHR V0, V1
+        let program = [0x80, 0x16];
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.v[0x0] = 0b100;
+        cpu.v[0x1] = 0b011;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.v[0x0], 0b001);
+        assert_eq!(cpu.v[0xF], 1);
+
+        let mut cpu = cpu_with_program(&program, Quirks::SUPER_CHIP);
+        cpu.v[0x0] = 0b100;
+        cpu.v[0x1] = 0b011;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.v[0x0], 0b010);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_load_store_quirk() {
+        // F255: LD [I], V2
+        let program = [0xF2, 0x55];
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.i = 0x300;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.i, 0x300);
+
+        let mut cpu = cpu_with_program(&program, Quirks::COSMAC_VIP);
+        cpu.i = 0x300;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.i, 0x303);
+    }
+
+    #[test]
+    fn test_jump_quirk() {
+        // B310: JP V0, 0x310
+        let program = [0xB3, 0x10];
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.v[0x0] = 0x1;
+        cpu.v[0x3] = 0x2;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x311);
+
+        let mut cpu = cpu_with_program(&program, Quirks::SUPER_CHIP);
+        cpu.v[0x0] = 0x1;
+        cpu.v[0x3] = 0x2;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x312);
+    }
+
+    #[test]
+    fn test_vf_reset_quirk() {
+        // 8011: OR V0, V1
+        let program = [0x80, 0x11];
+        let mut cpu = cpu_with_program(&program, Quirks::COSMAC_VIP);
+        cpu.v[0xF] = 0x1;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+
+        let mut cpu = cpu_with_program(&program, Quirks::SUPER_CHIP);
+        cpu.v[0xF] = 0x1;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_index_overflow_quirk() {
+        // F01E: ADD I, V0
+        let program = [0xF0, 0x1E];
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.i = 0xFFE;
+        cpu.v[0x0] = 0x2;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.i, 0x1000);
+        assert_eq!(cpu.v[0xF], 1);
+
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.i = 0xFFD;
+        cpu.v[0x0] = 0x2;
+        cpu.v[0xF] = 1;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.i, 0xFFF);
+        assert_eq!(cpu.v[0xF], 0);
+
+        let quirks = Quirks {
+            index_overflow: true,
+            ..Quirks::XO_CHIP
+        };
+        let mut cpu = cpu_with_program(&program, quirks);
+        cpu.i = 0xFFE;
+        cpu.v[0x0] = 0x2;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.i, 0x1000);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_hires() {
+        // 00FF: HIGH
+        // 00FE: LOW
+        let program = [0x00, 0xFF, 0x00, 0xFE];
+        let mut cpu = cpu_with_program(&program, Quirks::SUPER_CHIP);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.screen_resolution(), (128, 64));
+        assert_eq!(cpu.screen_buffer().len(), 128 * 64);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.screen_resolution(), (64, 32));
+    }
+
+    #[test]
+    fn test_exit() {
+        // 00FD: EXIT
+        let program = [0x00, 0xFD];
+        let mut cpu = cpu_with_program(&program, Quirks::SUPER_CHIP);
+        cpu.tick().unwrap();
+        assert!(cpu.has_exited());
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_flags() {
+        // F275: LD R, V2
+        // F285: LD V2, R
+        let program = [0xF2, 0x75, 0xF2, 0x85];
+        let mut cpu = cpu_with_program(&program, Quirks::SUPER_CHIP);
+        cpu.v[..3].copy_from_slice(&[1, 2, 3]);
+        cpu.tick().unwrap();
+        cpu.v[..3].fill(0);
+        cpu.tick().unwrap();
+        assert_eq!(&cpu.v[..4], &[1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn test_large_font() {
+        // F030: LD HF, V0
+        let program = [0xF0, 0x30];
+        let mut cpu = cpu_with_program(&program, Quirks::SUPER_CHIP);
+        cpu.v[0x0] = 0x2;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.i, fonts::LARGE_BASE_ADDRESS + 20);
+    }
+
+    #[test]
+    fn test_long_index() {
+        // 3000: SE V0, 0x00
+        // F000 1234: LD I, 0x1234
+        // F000 5678: LD I, 0x5678
+        let program = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0xF0, 0x00, 0x56, 0x78];
+        let mut cpu = cpu_with_program(&program, Quirks::XO_CHIP);
+        cpu.tick().unwrap();
+        // skips the whole 4 byte instruction
+        assert_eq!(cpu.pc, 0x206);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.i, 0x5678);
+        assert_eq!(cpu.pc, 0x20A);
+    }
+
+    #[test]
+    fn test_register_ranges() {
+        // 5132: SAVE V1 - V3
+        // 5313: LOAD V3 - V1
+        let program = [0x51, 0x32, 0x53, 0x13];
+        let mut cpu = cpu_with_program(&program, Quirks::XO_CHIP);
+        cpu.i = 0x300;
+        cpu.v[1..=3].copy_from_slice(&[1, 2, 3]);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.memory.read_u8(0x300), Ok(1));
+        assert_eq!(cpu.memory.read_u8(0x302), Ok(3));
+        cpu.tick().unwrap();
+        // loaded in reverse
+        assert_eq!(&cpu.v[1..=3], &[3, 2, 1]);
+        assert_eq!(cpu.i, 0x300);
+    }
+
+    #[test]
+    fn test_store_out_of_bounds() {
+        // F355: LD [I], V3
+        let program = [0xF3, 0x55];
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.i = 0xFFE;
+        assert_eq!(
+            cpu.tick(),
+            Err(CpuError::MemoryOutOfBounds { addr: 0x1000 })
+        );
+
+        let mut cpu = cpu_with_program(&program, Quirks::XO_CHIP);
+        cpu.i = 0xFFFE;
+        cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(
+            cpu.tick(),
+            Err(CpuError::MemoryOutOfBounds { addr: 0xFFFF })
+        );
+        assert_eq!(cpu.memory.read_u8(0xFFFF), Ok(2));
+        // doesn't wrap around into the fonts
+        assert_eq!(cpu.memory.read_u8(0x000), Ok(0x00));
+    }
+
+    #[test]
+    fn test_sound_timer() {
+        // F018: LD ST, V0
+        let program = [0xF0, 0x18];
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.v[0x0] = 1;
+        cpu.tick().unwrap();
+        // even a single frame of sound is played
+        assert!(cpu.is_sound_playing());
+        cpu.tick_timers();
+        assert!(!cpu.is_sound_playing());
+    }
+
+    #[test]
+    fn test_audio() {
+        // F002: AUDIO
+        // F03A: PITCH := V0
+        let program = [0xF0, 0x02, 0xF0, 0x3A];
+        let mut cpu = cpu_with_program(&program, Quirks::XO_CHIP);
+        assert_eq!(cpu.audio_pattern(), None);
+        assert_eq!(cpu.pitch(), DEFAULT_PITCH);
+        cpu.i = 0x300;
+        cpu.memory.load(&[0xAA; AUDIO_PATTERN_SIZE], 0x300).unwrap();
+        cpu.v[0x0] = 112;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.audio_pattern(), Some(&[0xAA; AUDIO_PATTERN_SIZE]));
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pitch(), 112);
+    }
+
+    #[test]
+    fn test_random() {
+        // C00F: RND V0, 0x0F
+        // C1F0: RND V1, 0xF0
+        let program = [0xC0, 0x0F, 0xC1, 0xF0];
+        let mut memory = Memory::new();
+        memory.load(&program, 0x200).unwrap();
+        let mut cpu = CPU::new(memory, Quirks::default(), Rng::scripted(vec![0xAB, 0xCD]));
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(cpu.v[0x0], 0x0B);
+        assert_eq!(cpu.v[0x1], 0xC0);
+    }
+
+    #[test]
+    fn test_reset() {
+        // 6202: LD V2, 0x02
+        // 00FF: HIGH
+        // F255: LD [I], V2
+        let program = [0x62, 0x02, 0x00, 0xFF, 0xF2, 0x55];
+        let mut cpu = cpu_with_program(&program, Quirks::default());
+        cpu.i = 0x300;
+        cpu.press_key(0x5, true);
+        for _ in 0..3 {
+            cpu.tick().unwrap();
+        }
+        cpu.reset();
+        assert_eq!(cpu.v[0x2], 0);
+        assert_eq!((cpu.pc, cpu.i), (0x200, 0));
+        assert!(!cpu.screen.is_hires());
+        assert!(cpu.keys[0x5]);
+        // memory written by the program is kept
+        assert_eq!(cpu.memory.read_u8(0x302), Ok(0x02));
+    }
+
+    #[test]
+    fn test_display_wait_quirk() {
+        // D001: DRW V0, V0, 1
+        // D001: DRW V0, V0, 1
+        let program = [0xD0, 0x01, 0xD0, 0x01];
+        let mut cpu = cpu_with_program(&program, Quirks::COSMAC_VIP);
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        // second draw has to wait for the next frame
+        assert_eq!(cpu.pc, 0x202);
+        cpu.tick_timers();
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x204);
+    }
+}
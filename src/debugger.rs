@@ -0,0 +1,228 @@
+use chip8::Machine;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+const PROMPT: &str = "(chip8) ";
+
+const HELP: &str = "\
+commands:
+  c, continue        resume execution
+  p, pause           pause execution
+  s, step [n]        execute n instructions (default 1)
+  b, break <addr>    set a breakpoint at addr (hex)
+  d, delete <addr>   delete the breakpoint at addr
+  l, list            list breakpoints
+  r, regs            print the registers
+  q, quit            exit the emulator
+  h, help            print this message";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Continue,
+    Pause,
+    Step(u32),
+    Break(u16),
+    Delete(u16),
+    List,
+    Registers,
+    Quit,
+    Help,
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address: {}", s))
+}
+
+/// Parse a line of input, blank lines are `None`.
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+    let arg = words.next();
+    let addr = || parse_addr(arg.ok_or("missing address")?);
+    let cmd = match name {
+        "c" | "continue" => Command::Continue,
+        "p" | "pause" => Command::Pause,
+        "s" | "step" => {
+            let n = match arg {
+                Some(n) => n.parse().map_err(|_| format!("invalid count: {}", n))?,
+                None => 1,
+            };
+            Command::Step(n)
+        }
+        "b" | "break" => Command::Break(addr()?),
+        "d" | "delete" => Command::Delete(addr()?),
+        "l" | "list" => Command::List,
+        "r" | "regs" => Command::Registers,
+        "q" | "quit" => Command::Quit,
+        "h" | "help" => Command::Help,
+        _ => return Err(format!("unknown command: {}, try help", name)),
+    };
+    Ok(Some(cmd))
+}
+
+fn prompt() {
+    print!("{}", PROMPT);
+    // nothing useful we can do if stdout is gone
+    let _ = io::stdout().flush();
+}
+
+fn print_registers(machine: &Machine) {
+    let cpu = machine.cpu();
+    println!(
+        "PC: 0x{:04X}  I: 0x{:04X}  SP: {}  DT: {}  ST: {}",
+        cpu.pc(),
+        cpu.i(),
+        cpu.sp(),
+        cpu.dt(),
+        cpu.st()
+    );
+    for (n, regs) in cpu.v().chunks(8).enumerate() {
+        let line: Vec<String> = regs
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("V{:X}: {:02X}", n * 8 + i, v))
+            .collect();
+        println!("{}", line.join("  "));
+    }
+    let stack: Vec<String> = cpu.stack().iter().map(|a| format!("0x{:04X}", a)).collect();
+    println!("stack: [{}]", stack.join(", "));
+}
+
+/// A terminal debugger that runs alongside the SDL window, commands are read
+/// from stdin on a separate thread so the window stays responsive.
+pub struct Debugger {
+    commands: Receiver<String>,
+    breakpoints: BTreeSet<u16>,
+    paused: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        println!("paused, type help for a list of commands");
+        prompt();
+        Self {
+            commands: rx,
+            breakpoints: BTreeSet::new(),
+            paused: true,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Handle any commands entered since the last call, returns false if the
+    /// user asked to quit.
+    pub fn poll(&mut self, machine: &mut Machine) -> bool {
+        while let Ok(line) = self.commands.try_recv() {
+            match parse_command(&line) {
+                Ok(Some(Command::Quit)) => return false,
+                Ok(Some(cmd)) => self.execute(cmd, machine),
+                Ok(None) => {}
+                Err(e) => println!("{}", e),
+            }
+            prompt();
+        }
+        true
+    }
+
+    fn execute(&mut self, cmd: Command, machine: &mut Machine) {
+        match cmd {
+            Command::Continue => {
+                // move off the current breakpoint so we don't stop on it again
+                if self.breakpoints.contains(&machine.cpu().pc()) {
+                    self.step(machine, 1);
+                }
+                self.paused = false;
+            }
+            Command::Pause => {
+                self.paused = true;
+                print_registers(machine);
+            }
+            Command::Step(n) => {
+                self.paused = true;
+                self.step(machine, n);
+                print_registers(machine);
+            }
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+                println!("breakpoint set at 0x{:04X}", addr);
+            }
+            Command::Delete(addr) => {
+                if self.breakpoints.remove(&addr) {
+                    println!("breakpoint deleted at 0x{:04X}", addr);
+                } else {
+                    println!("no breakpoint at 0x{:04X}", addr);
+                }
+            }
+            Command::List => {
+                for addr in &self.breakpoints {
+                    println!("0x{:04X}", addr);
+                }
+            }
+            Command::Registers => print_registers(machine),
+            Command::Help => println!("{}", HELP),
+            Command::Quit => {}
+        }
+    }
+
+    fn step(&mut self, machine: &mut Machine, n: u32) {
+        for _ in 0..n {
+            if let Err(e) = machine.step() {
+                println!("CPU error: {}", e);
+                break;
+            }
+        }
+    }
+
+    /// Run a frame unless paused, pausing on breakpoints and CPU errors
+    /// instead of exiting so the state can be inspected.
+    pub fn run_frame(&mut self, machine: &mut Machine) {
+        if self.paused {
+            return;
+        }
+        let breakpoints = &self.breakpoints;
+        match machine.run_frame_until(|cpu| breakpoints.contains(&cpu.pc())) {
+            Ok(false) => return,
+            Ok(true) => println!("\nbreakpoint at 0x{:04X}", machine.cpu().pc()),
+            Err(e) => println!("\nCPU error: {}", e),
+        }
+        self.paused = true;
+        print_registers(machine);
+        prompt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(""), Ok(None));
+        assert_eq!(parse_command("s"), Ok(Some(Command::Step(1))));
+        assert_eq!(parse_command("step 10"), Ok(Some(Command::Step(10))));
+        assert_eq!(parse_command("b 0x204"), Ok(Some(Command::Break(0x204))));
+        assert_eq!(
+            parse_command("delete 2A0"),
+            Ok(Some(Command::Delete(0x2A0)))
+        );
+        assert!(parse_command("b").is_err());
+        assert!(parse_command("b xyz").is_err());
+        assert!(parse_command("nope").is_err());
+    }
+}
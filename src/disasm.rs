@@ -0,0 +1,178 @@
+use crate::cpu::{decode, Instruction, LONG_INDEX_OPCODE};
+use crate::rom;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// maximum number of data bytes shown on a single line
+const DATA_PER_LINE: usize = 4;
+
+/// A single line of a disassembly listing, either an instruction or data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: String = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(f, "0x{:04X}  {:<8}  {}", self.addr, hex, self.text)
+    }
+}
+
+fn read_u16(rom: &[u8], offset: usize) -> Option<u16> {
+    let bytes = rom.get(offset..offset + 2)?;
+    Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+}
+
+fn address(offset: usize) -> u16 {
+    rom::BASE_ADDRESS.wrapping_add(offset as u16)
+}
+
+/// Decode the instruction at `offset`, returning it along with its text and
+/// length in bytes.
+fn decode_at(rom: &[u8], offset: usize) -> Option<(Instruction, String, usize)> {
+    let inst = decode(read_u16(rom, offset)?)?;
+    if inst == Instruction::SetLongIndex {
+        let addr = read_u16(rom, offset + 2)?;
+        Some((inst, format!("LD I, 0x{:04X}", addr), 4))
+    } else {
+        Some((inst, inst.to_string(), 2))
+    }
+}
+
+fn data_line(rom: &[u8], offset: usize, len: usize) -> Line {
+    let bytes = rom[offset..offset + len].to_vec();
+    let values: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+    Line {
+        addr: address(offset),
+        bytes,
+        text: format!("DB {}", values.join(", ")),
+    }
+}
+
+/// Find the offsets of every instruction reachable from the start of the
+/// ROM by following jumps, calls and skips.
+fn reachable(rom: &[u8]) -> BTreeSet<usize> {
+    use Instruction::*;
+    let to_offset = |addr: u16| addr.checked_sub(rom::BASE_ADDRESS).map(|o| o as usize);
+    let mut code = BTreeSet::new();
+    let mut pending = vec![0];
+    while let Some(offset) = pending.pop() {
+        if code.contains(&offset) {
+            continue;
+        }
+        let Some((inst, _, len)) = decode_at(rom, offset) else {
+            continue;
+        };
+        code.insert(offset);
+        let next = offset + len;
+        match inst {
+            Jump(addr) => pending.extend(to_offset(addr)),
+            Call(addr) => {
+                pending.extend(to_offset(addr));
+                pending.push(next);
+            }
+            SkipIfEq(..) | SkipIfNe(..) | SkipIfVxVyEq(..) | SkipIfVxVyNe(..)
+            | SkipIfPressed(_) | SkipIfNotPressed(_) => {
+                let skipped = if read_u16(rom, next) == Some(LONG_INDEX_OPCODE) {
+                    4
+                } else {
+                    2
+                };
+                pending.push(next);
+                pending.push(next + skipped);
+            }
+            // the target of JP V0 depends on runtime state
+            Return | Exit | JumpV0(_) => {}
+            _ => pending.push(next),
+        }
+    }
+    code
+}
+
+/// Disassemble a ROM loaded at `rom::BASE_ADDRESS`.
+///
+/// By default every 2 bytes are decoded in order, anything that isn't a valid
+/// instruction is shown as data. With `follow` set only the instructions
+/// reachable from the entry point are decoded and everything else is data.
+pub fn disassemble(rom: &[u8], follow: bool) -> Vec<Line> {
+    let code = follow.then(|| reachable(rom));
+    let is_code = |offset: usize| code.as_ref().is_none_or(|c| c.contains(&offset));
+    let mut lines = vec![];
+    let mut offset = 0;
+    while offset < rom.len() {
+        if is_code(offset) {
+            if let Some((_, text, len)) = decode_at(rom, offset) {
+                lines.push(Line {
+                    addr: address(offset),
+                    bytes: rom[offset..offset + len].to_vec(),
+                    text,
+                });
+                offset += len;
+                continue;
+            }
+        }
+        let len = if follow {
+            // group data up until the next instruction
+            (offset + 1..rom.len())
+                .take(DATA_PER_LINE - 1)
+                .take_while(|o| !is_code(*o))
+                .count()
+                + 1
+        } else {
+            // keep to instruction alignment
+            (rom.len() - offset).min(2)
+        };
+        lines.push(data_line(rom, offset, len));
+        offset += len;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(rom: &[u8], follow: bool) -> Vec<String> {
+        disassemble(rom, follow)
+            .iter()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_linear() {
+        let rom = [0x6A, 0x02, 0xFF, 0xFF, 0xF0, 0x00, 0x12, 0x34, 0x00];
+        assert_eq!(
+            listing(&rom, false),
+            vec![
+                "0x0200  6A02      LD VA, 0x02",
+                "0x0202  FFFF      DB 0xFF, 0xFF",
+                "0x0204  F0001234  LD I, 0x1234",
+                "0x0208  00        DB 0x00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_follow() {
+        // 2206: CALL 0x206
+        // 1202: JP 0x202
+        // 00E0: unreachable
+        // 00EE: RET
+        // 0xFF, 0x00: data
+        let rom = [0x22, 0x06, 0x12, 0x02, 0x00, 0xE0, 0x00, 0xEE, 0xFF, 0x00];
+        assert_eq!(
+            listing(&rom, true),
+            vec![
+                "0x0200  2206      CALL 0x206",
+                "0x0202  1202      JP 0x202",
+                "0x0204  00E0      DB 0x00, 0xE0",
+                "0x0206  00EE      RET",
+                "0x0208  FF00      DB 0xFF, 0x00",
+            ]
+        );
+    }
+}
@@ -0,0 +1,32 @@
+//! Helpers shared by the window and terminal frontends.
+
+use chip8::machine::TARGET_FPS;
+use chip8::pacer::Pacer;
+use chip8::{rom, Machine};
+use std::path::PathBuf;
+
+/// Most frames run in one go to catch up after a stall, beyond this the
+/// emulation slows down instead.
+const MAX_CATCH_UP_FRAMES: u32 = 4;
+
+/// A pacer for running frames at 60 a second.
+pub fn pacer() -> Pacer {
+    Pacer::new(TARGET_FPS, MAX_CATCH_UP_FRAMES)
+}
+
+/// Reset the CPU and screen, or with `hard` reload the ROM from disk and
+/// start over from power on.
+pub fn reset(
+    machine: &mut Machine,
+    rom_path: &PathBuf,
+    hard: bool,
+) -> Result<&'static str, String> {
+    if hard {
+        let rom = rom::load(rom_path).map_err(|e| e.to_string())?;
+        machine.hard_reset(&rom).map_err(|e| e.to_string())?;
+        Ok("hard reset")
+    } else {
+        machine.soft_reset();
+        Ok("soft reset")
+    }
+}
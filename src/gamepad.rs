@@ -0,0 +1,238 @@
+use crate::keymap::{Held, Keymap, PadInput, Source};
+use sdl2::controller::{Axis, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+use std::collections::HashMap;
+
+/// How far a stick or trigger has to move, out of 32767, before it counts as
+/// pushed.
+const AXIS_THRESHOLD: i16 = 16384;
+
+/// Game controllers plugged in, opened as they're connected and closed when
+/// they're removed.
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    /// by joystick id
+    controllers: HashMap<u32, GameController>,
+}
+
+impl Gamepads {
+    /// SDL sends an added event for controllers that are already plugged in,
+    /// so these are opened by `handle_event` too.
+    pub fn new(subsystem: GameControllerSubsystem) -> Self {
+        Self {
+            subsystem,
+            controllers: HashMap::new(),
+        }
+    }
+
+    /// Handle a controller event, opening and closing controllers as
+    /// they're plugged in and out, and returning the keys it changes, see
+    /// `key_changes`.
+    pub fn handle_event(
+        &mut self,
+        keymap: &Keymap,
+        held: &mut Held,
+        event: &Event,
+    ) -> Vec<(u8, bool)> {
+        match *event {
+            // a controller that can't be opened is left unused
+            Event::ControllerDeviceAdded { which, .. } => {
+                if let Ok(controller) = self.subsystem.open(which) {
+                    self.controllers
+                        .insert(controller.instance_id(), controller);
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers.remove(&which);
+            }
+            _ => {}
+        }
+        key_changes(keymap, held, event)
+    }
+}
+
+/// The keys pressed (true) or released (false) by a controller event, keys
+/// stay pressed while anything else in `held` still holds them. Unplugging a
+/// controller releases whatever was held on it, other events are ignored.
+fn key_changes(keymap: &Keymap, held: &mut Held, event: &Event) -> Vec<(u8, bool)> {
+    if let Event::ControllerDeviceRemoved { which, .. } = *event {
+        return held.remove_pad(keymap, which);
+    }
+    pad_inputs(event)
+        .into_iter()
+        .filter_map(|(source, pushed)| held.set(keymap, source, pushed))
+        .collect()
+}
+
+/// The controller inputs a button or axis event pushes (true) or lets go of
+/// (false).
+fn pad_inputs(event: &Event) -> Vec<(Source, bool)> {
+    match *event {
+        Event::ControllerButtonDown { which, button, .. } => {
+            vec![(Source::Pad(which, PadInput::Button(button)), true)]
+        }
+        Event::ControllerButtonUp { which, button, .. } => {
+            vec![(Source::Pad(which, PadInput::Button(button)), false)]
+        }
+        Event::ControllerAxisMotion {
+            which, axis, value, ..
+        } => axis_changes(axis, value)
+            .into_iter()
+            .map(|(input, pushed)| (Source::Pad(which, input), pushed))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Both directions of an axis, and whether each is pushed at `value`.
+fn axis_changes(axis: Axis, value: i16) -> [(PadInput, bool); 2] {
+    [
+        (PadInput::Axis(axis, false), value < -AXIS_THRESHOLD),
+        (PadInput::Axis(axis, true), value > AXIS_THRESHOLD),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::controller::Button;
+
+    fn button(which: u32, button: Button, down: bool) -> Event {
+        match down {
+            true => Event::ControllerButtonDown {
+                timestamp: 0,
+                which,
+                button,
+            },
+            false => Event::ControllerButtonUp {
+                timestamp: 0,
+                which,
+                button,
+            },
+        }
+    }
+
+    fn axis(which: u32, axis: Axis, value: i16) -> Event {
+        Event::ControllerAxisMotion {
+            timestamp: 0,
+            which,
+            axis,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_pad_inputs() {
+        let a = Source::Pad(1, PadInput::Button(Button::A));
+        assert_eq!(pad_inputs(&button(1, Button::A, true)), vec![(a, true)]);
+        assert_eq!(pad_inputs(&button(1, Button::A, false)), vec![(a, false)]);
+        assert_eq!(
+            pad_inputs(&axis(2, Axis::LeftX, i16::MIN)),
+            vec![
+                (Source::Pad(2, PadInput::Axis(Axis::LeftX, false)), true),
+                (Source::Pad(2, PadInput::Axis(Axis::LeftX, true)), false),
+            ]
+        );
+        assert_eq!(pad_inputs(&Event::Quit { timestamp: 0 }), vec![]);
+    }
+
+    #[test]
+    fn test_key_changes() {
+        let keymap = Keymap::default();
+        let mut held = Held::default();
+        let mut changes = |event| key_changes(&keymap, &mut held, &event);
+        assert_eq!(changes(button(0, Button::A, true)), vec![(0x5, true)]);
+        assert_eq!(changes(button(0, Button::A, false)), vec![(0x5, false)]);
+        // the stick only counts once it's pushed past the threshold
+        assert_eq!(changes(axis(0, Axis::LeftX, AXIS_THRESHOLD / 2)), vec![]);
+        assert_eq!(changes(axis(0, Axis::LeftX, i16::MAX)), vec![(0x6, true)]);
+        // pushing straight over to the other side releases one key and
+        // presses another
+        assert_eq!(
+            changes(axis(0, Axis::LeftX, i16::MIN)),
+            vec![(0x4, true), (0x6, false)]
+        );
+    }
+
+    #[test]
+    fn test_unplug() {
+        let keymap = Keymap::default();
+        let mut held = Held::default();
+        let mut changes = |event| key_changes(&keymap, &mut held, &event);
+        changes(button(0, Button::A, true));
+        changes(button(1, Button::B, true));
+        let removed = Event::ControllerDeviceRemoved {
+            timestamp: 0,
+            which: 0,
+        };
+        // only the unplugged controller's keys are released
+        assert_eq!(changes(removed.clone()), vec![(0x5, false)]);
+        assert_eq!(changes(removed), vec![]);
+        assert_eq!(changes(button(1, Button::B, false)), vec![(0x0, false)]);
+    }
+
+    #[test]
+    fn test_axis_changes() {
+        let changes = |value| axis_changes(Axis::LeftY, value).map(|(_, held)| held);
+        assert_eq!(changes(0), [false, false]);
+        assert_eq!(changes(-AXIS_THRESHOLD - 1), [true, false]);
+        assert_eq!(changes(i16::MAX), [false, true]);
+    }
+
+    /// Plugs in a virtual controller, presses buttons and unplugs it. This
+    /// needs a real SDL library (2.0.14 or later) so it's only run with
+    /// `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_virtual_controller() {
+        use sdl2::sys;
+
+        let sdl = sdl2::init().unwrap();
+        let mut gamepads = Gamepads::new(sdl.game_controller().unwrap());
+        let mut events = sdl.event_pump().unwrap();
+        let keymap = Keymap::default();
+        let mut held = Held::default();
+        let mut pump = |gamepads: &mut Gamepads| -> Vec<(u8, bool)> {
+            events
+                .poll_iter()
+                .flat_map(|event| gamepads.handle_event(&keymap, &mut held, &event))
+                .collect()
+        };
+
+        let axes = sys::SDL_GameControllerAxis::SDL_CONTROLLER_AXIS_MAX as i32;
+        let buttons = sys::SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_MAX as i32;
+        let index = unsafe {
+            sys::SDL_JoystickAttachVirtual(
+                sys::SDL_JoystickType::SDL_JOYSTICK_TYPE_GAMECONTROLLER,
+                axes,
+                buttons,
+                0,
+            )
+        };
+        assert!(index >= 0, "{}", sdl2::get_error());
+        let joystick = unsafe { sys::SDL_JoystickOpen(index) };
+        assert!(!joystick.is_null());
+        pump(&mut gamepads);
+        assert_eq!(gamepads.controllers.len(), 1);
+
+        let set_button = |button: Button, down: bool| unsafe {
+            sys::SDL_JoystickSetVirtualButton(joystick, button as i32, down as u8);
+        };
+        set_button(Button::A, true);
+        assert_eq!(pump(&mut gamepads), vec![(0x5, true)]);
+        set_button(Button::A, false);
+        assert_eq!(pump(&mut gamepads), vec![(0x5, false)]);
+
+        unsafe { sys::SDL_JoystickSetVirtualAxis(joystick, Axis::LeftX as i32, i16::MAX) };
+        assert_eq!(pump(&mut gamepads), vec![(0x6, true)]);
+
+        // unplugging releases whatever was held
+        unsafe {
+            sys::SDL_JoystickClose(joystick);
+            sys::SDL_JoystickDetachVirtual(index);
+        }
+        assert_eq!(pump(&mut gamepads), vec![(0x6, false)]);
+        assert!(gamepads.controllers.is_empty());
+    }
+}
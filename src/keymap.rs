@@ -0,0 +1,604 @@
+use sdl2::controller::{Axis, Button};
+use sdl2::keyboard::Scancode;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::path::Path;
+
+/// Names of the scancodes keys can be bound to, as they're spelled in keymap
+/// files. They match case insensitively.
+macro_rules! scancode_names {
+    ($($name:ident),* $(,)?) => {
+        &[$((stringify!($name), Scancode::$name)),*]
+    };
+}
+
+#[rustfmt::skip]
+const SCANCODES: &[(&str, Scancode)] = scancode_names![
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9, Num0,
+    Return, Escape, Backspace, Tab, Space, Minus, Equals, LeftBracket, RightBracket,
+    Backslash, NonUsHash, Semicolon, Apostrophe, Grave, Comma, Period, Slash, CapsLock,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    PrintScreen, ScrollLock, Pause, Insert, Home, PageUp, Delete, End, PageDown,
+    Right, Left, Down, Up, NumLockClear,
+    KpDivide, KpMultiply, KpMinus, KpPlus, KpEnter,
+    Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9, Kp0, KpPeriod, KpEquals, KpComma,
+    NonUsBackslash, Application, Menu,
+    LCtrl, LShift, LAlt, LGui, RCtrl, RShift, RAlt, RGui,
+];
+
+/// Look up a scancode by name, digits can also be given on their own.
+fn scancode_from_name(name: &str) -> Option<Scancode> {
+    let name = match name {
+        digit if digit.len() == 1 && digit.as_bytes()[0].is_ascii_digit() => {
+            format!("Num{}", digit)
+        }
+        _ => name.to_string(),
+    };
+    SCANCODES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(&name))
+        .map(|(_, scancode)| *scancode)
+}
+
+fn scancode_name(scancode: Scancode) -> &'static str {
+    SCANCODES
+        .iter()
+        .find(|(_, s)| *s == scancode)
+        .map_or("?", |(name, _)| name)
+}
+
+/// A button, or a stick or trigger pushed in one direction, on a game
+/// controller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PadInput {
+    Button(Button),
+    /// an axis pushed towards its positive end if true, down and right are
+    /// positive for the sticks
+    Axis(Axis, bool),
+}
+
+/// Something that presses keys, a key on the keyboard or an input on the game
+/// controller with the given joystick id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    Key(Scancode),
+    Pad(u32, PadInput),
+}
+
+/// Names of the game controller inputs keys can be bound to, matched case
+/// insensitively like scancodes.
+const PAD_INPUTS: &[(&str, PadInput)] = {
+    use PadInput::{Axis as A, Button as B};
+    &[
+        ("A", B(Button::A)),
+        ("B", B(Button::B)),
+        ("X", B(Button::X)),
+        ("Y", B(Button::Y)),
+        ("Back", B(Button::Back)),
+        ("Guide", B(Button::Guide)),
+        ("Start", B(Button::Start)),
+        ("LeftStick", B(Button::LeftStick)),
+        ("RightStick", B(Button::RightStick)),
+        ("LeftShoulder", B(Button::LeftShoulder)),
+        ("RightShoulder", B(Button::RightShoulder)),
+        ("DPadUp", B(Button::DPadUp)),
+        ("DPadDown", B(Button::DPadDown)),
+        ("DPadLeft", B(Button::DPadLeft)),
+        ("DPadRight", B(Button::DPadRight)),
+        ("Misc1", B(Button::Misc1)),
+        ("Paddle1", B(Button::Paddle1)),
+        ("Paddle2", B(Button::Paddle2)),
+        ("Paddle3", B(Button::Paddle3)),
+        ("Paddle4", B(Button::Paddle4)),
+        ("Touchpad", B(Button::Touchpad)),
+        ("LeftStickUp", A(Axis::LeftY, false)),
+        ("LeftStickDown", A(Axis::LeftY, true)),
+        ("LeftStickLeft", A(Axis::LeftX, false)),
+        ("LeftStickRight", A(Axis::LeftX, true)),
+        ("RightStickUp", A(Axis::RightY, false)),
+        ("RightStickDown", A(Axis::RightY, true)),
+        ("RightStickLeft", A(Axis::RightX, false)),
+        ("RightStickRight", A(Axis::RightX, true)),
+        ("LeftTrigger", A(Axis::TriggerLeft, true)),
+        ("RightTrigger", A(Axis::TriggerRight, true)),
+    ]
+};
+
+fn pad_input_from_name(name: &str) -> Option<PadInput> {
+    PAD_INPUTS
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, input)| *input)
+}
+
+fn pad_input_name(input: PadInput) -> &'static str {
+    PAD_INPUTS
+        .iter()
+        .find(|(_, i)| *i == input)
+        .map_or("?", |(name, _)| name)
+}
+
+/// Game controller bindings used unless a keymap file changes them. The
+/// D-pad and left stick are 2/4/6/8, the directions on the keypad, with A
+/// on 5 in the middle and B on 0.
+const DEFAULT_PAD: &[(&str, u8)] = &[
+    ("DPadUp", 0x2),
+    ("LeftStickUp", 0x2),
+    ("DPadLeft", 0x4),
+    ("LeftStickLeft", 0x4),
+    ("DPadRight", 0x6),
+    ("LeftStickRight", 0x6),
+    ("DPadDown", 0x8),
+    ("LeftStickDown", 0x8),
+    ("A", 0x5),
+    ("B", 0x0),
+];
+
+/// Bind `key` to `inputs`, replacing its old bindings. Inputs taken from
+/// keys that haven't been `rebound` yet silently move, it's only an error to
+/// bind an input twice in the same file.
+fn bind<T: Copy + Eq + Hash>(
+    bindings: &mut HashMap<T, u8>,
+    rebound: &mut [bool; 16],
+    key: u8,
+    inputs: Vec<T>,
+    name: fn(T) -> &'static str,
+) -> Result<(), String> {
+    bindings.retain(|_, k| *k != key);
+    for input in inputs {
+        if let Some(other) = bindings.insert(input, key) {
+            if other != key && rebound[other as usize] {
+                return Err(format!(
+                    "{} is bound to both {:X} and {:X}",
+                    name(input),
+                    other,
+                    key
+                ));
+            }
+        }
+    }
+    rebound[key as usize] = true;
+    Ok(())
+}
+
+/// Built in layouts, as the scancodes for keys 0 to F. Scancodes are key
+/// positions rather than the letters printed on them, so these work the same
+/// on any keyboard layout.
+const PRESETS: &[(&str, [Scancode; 16])] = {
+    use Scancode::*;
+    &[
+        // 1 2 3 C      1 2 3 4
+        // 4 5 6 D  =>  Q W E R
+        // 7 8 9 E      A S D F
+        // A 0 B F      Z X C V
+        (
+            "qwerty",
+            [X, Num1, Num2, Num3, Q, W, E, A, S, D, Z, C, Num4, R, F, V],
+        ),
+        // digits on the number pad, A to F on the operators around it
+        (
+            "numpad",
+            [
+                Kp0, Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9, KpDivide, KpMultiply, KpMinus,
+                KpPlus, KpEnter, KpPeriod,
+            ],
+        ),
+    ]
+};
+
+/// Which scancodes and game controller inputs press which of the 16 keys. A
+/// key can have any number of bindings, but each scancode or input presses at
+/// most one key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    keys: HashMap<Scancode, u8>,
+    pad: HashMap<PadInput, u8>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::preset("qwerty").unwrap()
+    }
+}
+
+impl Keymap {
+    /// One of the built in layouts: qwerty or numpad.
+    pub fn preset(name: &str) -> Option<Self> {
+        let (_, bindings) = PRESETS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        let keys = bindings
+            .iter()
+            .enumerate()
+            .map(|(key, scancode)| (*scancode, key as u8))
+            .collect();
+        let pad = DEFAULT_PAD
+            .iter()
+            .map(|(name, key)| (pad_input_from_name(name).unwrap(), *key))
+            .collect();
+        Some(Self { keys, pad })
+    }
+
+    /// A preset by name, or else a keymap file.
+    pub fn load(preset_or_path: &str) -> Result<Self, String> {
+        if let Some(keymap) = Self::preset(preset_or_path) {
+            return Ok(keymap);
+        }
+        Self::load_file(Path::new(preset_or_path)).map_err(|e| {
+            let presets: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+            format!("{} (presets are {})", e, presets.join(", "))
+        })
+    }
+
+    /// The keymap to play `rom_path` with: `keymap` if given, a preset or
+    /// path, otherwise the ROM's own profile next to it
+    /// (`roms/GAME.keymap`) if there is one, or else the default layout.
+    pub fn for_rom(keymap: Option<&str>, rom_path: &Path) -> Result<Self, String> {
+        let profile = rom_path.with_extension("keymap");
+        match keymap {
+            Some(keymap) => Self::load(keymap),
+            None if profile.exists() => Self::load_file(&profile),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load_file(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read keymap {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}:{}", path.display(), e))
+    }
+
+    /// Parse the `[keymap]` and `[gamepad]` sections of a config file, other
+    /// sections are ignored. Each line binds a key to one or more scancodes or
+    /// game controller inputs, replacing its bindings in the preset the keymap
+    /// starts from:
+    ///
+    /// ```text
+    /// [keymap]
+    /// preset = numpad
+    /// 5 = Kp5 Up
+    /// A = Space
+    ///
+    /// [gamepad]
+    /// 5 = A DPadUp
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut keymap = Self::default();
+        let mut section = "";
+        let mut rebound = [false; 16];
+        let mut pad_rebound = [false; 16];
+        for (n, line) in text.lines().enumerate() {
+            let error = |message: String| format!("{}: {}", n + 1, message);
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = match name.trim() {
+                    "keymap" => "keymap",
+                    "gamepad" => "gamepad",
+                    _ => "",
+                };
+                continue;
+            }
+            if section.is_empty() {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(error(format!("expected KEY = INPUTS, found {:?}", line)));
+            };
+            let (name, value) = (name.trim(), value.trim());
+            if name == "preset" && section == "keymap" {
+                if rebound.iter().any(|r| *r) {
+                    return Err(error("preset must come before any keys".to_string()));
+                }
+                let pad = keymap.pad;
+                keymap = Self::preset(value)
+                    .ok_or_else(|| error(format!("unknown preset {:?}", value)))?;
+                keymap.pad = pad;
+                continue;
+            }
+            let key = match u8::from_str_radix(name, 16) {
+                Ok(key) if name.len() == 1 => key,
+                _ => return Err(error(format!("unknown key {:?}, expected 0 to F", name))),
+            };
+            let names: Vec<&str> = value
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .collect();
+            if names.is_empty() {
+                return Err(error(format!("nothing given to bind key {:X} to", key)));
+            }
+            let result = if section == "keymap" {
+                let scancodes = names
+                    .iter()
+                    .map(|s| scancode_from_name(s).ok_or(format!("unknown scancode {:?}", s)))
+                    .collect::<Result<Vec<_>, _>>();
+                scancodes.and_then(|scancodes| {
+                    bind(
+                        &mut keymap.keys,
+                        &mut rebound,
+                        key,
+                        scancodes,
+                        scancode_name,
+                    )
+                })
+            } else {
+                let inputs = names
+                    .iter()
+                    .map(|s| {
+                        pad_input_from_name(s).ok_or(format!("unknown controller input {:?}", s))
+                    })
+                    .collect::<Result<Vec<_>, _>>();
+                inputs.and_then(|inputs| {
+                    bind(
+                        &mut keymap.pad,
+                        &mut pad_rebound,
+                        key,
+                        inputs,
+                        pad_input_name,
+                    )
+                })
+            };
+            result.map_err(error)?;
+        }
+        Ok(keymap)
+    }
+
+    /// The key `scancode` presses.
+    pub fn key(&self, scancode: Scancode) -> Option<u8> {
+        self.keys.get(&scancode).copied()
+    }
+
+    /// The key a game controller input presses.
+    pub fn pad_key(&self, input: PadInput) -> Option<u8> {
+        self.pad.get(&input).copied()
+    }
+
+    /// The key `source` presses.
+    pub fn source_key(&self, source: Source) -> Option<u8> {
+        match source {
+            Source::Key(scancode) => self.key(scancode),
+            Source::Pad(_, input) => self.pad_key(input),
+        }
+    }
+
+    /// Fail if any of `reserved` is bound, these are used by the emulator.
+    pub fn check_reserved(&self, reserved: &[Scancode]) -> Result<(), String> {
+        match reserved.iter().find(|s| self.keys.contains_key(s)) {
+            Some(scancode) => Err(format!(
+                "{} can't be bound to key {:X}, the emulator uses it",
+                scancode_name(*scancode),
+                self.keys[scancode]
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The keyboard keys and controller inputs held down, to work out when keys
+/// change. Several sources can be bound to the same key, which stays pressed
+/// until all of them are released.
+#[derive(Debug, Default)]
+pub struct Held {
+    sources: HashSet<Source>,
+}
+
+impl Held {
+    fn is_key_held(&self, keymap: &Keymap, key: u8) -> bool {
+        self.sources
+            .iter()
+            .any(|source| keymap.source_key(*source) == Some(key))
+    }
+
+    /// Hold or release `source`, returning the key it changes.
+    pub fn set(&mut self, keymap: &Keymap, source: Source, held: bool) -> Option<(u8, bool)> {
+        let key = keymap.source_key(source)?;
+        let before = self.is_key_held(keymap, key);
+        if held {
+            self.sources.insert(source);
+        } else {
+            self.sources.remove(&source);
+        }
+        let after = self.is_key_held(keymap, key);
+        (before != after).then_some((key, after))
+    }
+
+    /// Release everything held on a controller that's gone away.
+    pub fn remove_pad(&mut self, keymap: &Keymap, id: u32) -> Vec<(u8, bool)> {
+        let sources: Vec<Source> = self
+            .sources
+            .iter()
+            .filter(|source| matches!(source, Source::Pad(i, _) if *i == id))
+            .copied()
+            .collect();
+        sources
+            .into_iter()
+            .filter_map(|source| self.set(keymap, source, false))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.key(Scancode::Num1), Some(0x1));
+        assert_eq!(keymap.key(Scancode::V), Some(0xF));
+        assert_eq!(keymap.key(Scancode::P), None);
+        assert_eq!(keymap.keys.len(), 16);
+        let numpad = Keymap::preset("numpad").unwrap();
+        assert_eq!(numpad.key(Scancode::Kp7), Some(0x7));
+        assert_eq!(numpad.key(Scancode::KpEnter), Some(0xE));
+        assert!(Keymap::preset("dvorak").is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "
+            [video]
+            1 = ignored
+            [keymap]  # comment
+            preset = numpad
+            5 = kp5, Up
+            a = space 0
+        ";
+        let keymap = Keymap::parse(text).unwrap();
+        assert_eq!(keymap.key(Scancode::Kp5), Some(0x5));
+        assert_eq!(keymap.key(Scancode::Up), Some(0x5));
+        assert_eq!(keymap.key(Scancode::Space), Some(0xA));
+        assert_eq!(keymap.key(Scancode::Num0), Some(0xA));
+        // the preset's binding for A is replaced, the rest are kept
+        assert_eq!(keymap.key(Scancode::KpDivide), None);
+        assert_eq!(keymap.key(Scancode::Kp9), Some(0x9));
+        // starts from the default layout without a preset
+        assert_eq!(Keymap::parse("[keymap]\n").unwrap(), Keymap::default());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| Keymap::parse(&format!("[keymap]\n{}", text)).unwrap_err();
+        assert_eq!(error("G = A"), "2: unknown key \"G\", expected 0 to F");
+        assert_eq!(error("1 = Foo"), "2: unknown scancode \"Foo\"");
+        assert_eq!(error("1 ="), "2: nothing given to bind key 1 to");
+        assert_eq!(error("1 A"), "2: expected KEY = INPUTS, found \"1 A\"");
+        assert_eq!(error("preset = dvorak"), "2: unknown preset \"dvorak\"");
+        assert_eq!(error("1 = P\n2 = P"), "3: P is bound to both 1 and 2");
+        assert_eq!(
+            error("1 = P\npreset = numpad"),
+            "3: preset must come before any keys"
+        );
+    }
+
+    #[test]
+    fn test_parse_gamepad() {
+        let text = "
+            [gamepad]
+            5 = a, RightTrigger
+            0 = DPadUp
+            [keymap]
+            preset = numpad
+        ";
+        let keymap = Keymap::parse(text).unwrap();
+        assert_eq!(keymap.pad_key(PadInput::Button(Button::A)), Some(0x5));
+        assert_eq!(
+            keymap.pad_key(PadInput::Axis(Axis::TriggerRight, true)),
+            Some(0x5)
+        );
+        // moved from 2, the rest of the defaults are kept
+        assert_eq!(keymap.pad_key(PadInput::Button(Button::DPadUp)), Some(0x0));
+        assert_eq!(keymap.pad_key(PadInput::Button(Button::B)), None);
+        assert_eq!(
+            keymap.pad_key(PadInput::Axis(Axis::LeftY, false)),
+            Some(0x2)
+        );
+        // a keyboard preset doesn't reset the controller
+        assert_eq!(keymap.key(Scancode::Kp1), Some(0x1));
+
+        let error = Keymap::parse("[gamepad]\n1 = Foo").unwrap_err();
+        assert_eq!(error, "2: unknown controller input \"Foo\"");
+        let error = Keymap::parse("[gamepad]\n1 = A\n2 = A").unwrap_err();
+        assert_eq!(error, "3: A is bound to both 1 and 2");
+    }
+
+    #[test]
+    fn test_rebinding_a_preset_key() {
+        // Q presses 4 in the default layout, taking it for 1 moves it
+        let keymap = Keymap::parse("[keymap]\n1 = Q").unwrap();
+        assert_eq!(keymap.key(Scancode::Q), Some(0x1));
+        assert_eq!(keymap.key(Scancode::Num1), None);
+    }
+
+    #[test]
+    fn test_check_reserved() {
+        let keymap = Keymap::parse("[keymap]\n1 = Escape").unwrap();
+        assert_eq!(
+            keymap.check_reserved(&[Scancode::Escape]),
+            Err("Escape can't be bound to key 1, the emulator uses it".to_string())
+        );
+        assert_eq!(
+            Keymap::default().check_reserved(&[Scancode::Escape]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_for_rom() {
+        let dir = std::env::temp_dir().join(format!("chip8-keymap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("GAME.ch8");
+        let numpad = Keymap::preset("numpad").unwrap();
+        assert_eq!(Keymap::for_rom(None, &rom_path), Ok(Keymap::default()));
+
+        std::fs::write(dir.join("GAME.keymap"), "[keymap]\npreset = numpad").unwrap();
+        let profile = Keymap::for_rom(None, &rom_path);
+        // --keymap wins over the profile
+        let given = Keymap::for_rom(Some("qwerty"), &rom_path);
+        let other = Keymap::for_rom(None, &dir.join("OTHER.ch8"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(profile, Ok(numpad));
+        assert_eq!(given, Ok(Keymap::default()));
+        assert_eq!(other, Ok(Keymap::default()));
+    }
+
+    const A: Source = Source::Pad(0, PadInput::Button(Button::A));
+    const UP: Source = Source::Pad(0, PadInput::Button(Button::DPadUp));
+    const STICK_UP: Source = Source::Pad(1, PadInput::Axis(Axis::LeftY, false));
+
+    #[test]
+    fn test_held() {
+        let keymap = Keymap::default();
+        let mut held = Held::default();
+        assert_eq!(held.set(&keymap, A, true), Some((0x5, true)));
+        assert_eq!(held.set(&keymap, A, true), None);
+        assert_eq!(held.set(&keymap, A, false), Some((0x5, false)));
+        // unbound inputs do nothing
+        let guide = Source::Pad(0, PadInput::Button(Button::Guide));
+        assert_eq!(held.set(&keymap, guide, true), None);
+        assert_eq!(held.set(&keymap, Source::Key(Scancode::P), true), None);
+    }
+
+    #[test]
+    fn test_shared_key() {
+        // the D-pad and stick both press 2, it's released when both are
+        let keymap = Keymap::default();
+        let mut held = Held::default();
+        assert_eq!(held.set(&keymap, UP, true), Some((0x2, true)));
+        assert_eq!(held.set(&keymap, STICK_UP, true), None);
+        assert_eq!(held.set(&keymap, UP, false), None);
+        assert_eq!(held.set(&keymap, STICK_UP, false), Some((0x2, false)));
+    }
+
+    #[test]
+    fn test_keyboard_and_pad() {
+        // W and the D-pad both press 5 here, letting go of the pad leaves it
+        // pressed while W is still down
+        let keymap = Keymap::parse(
+            "[keymap]
+5 = W
+[gamepad]
+5 = DPadUp",
+        )
+        .unwrap();
+        let mut held = Held::default();
+        let w = Source::Key(Scancode::W);
+        assert_eq!(held.set(&keymap, w, true), Some((0x5, true)));
+        assert_eq!(held.set(&keymap, UP, true), None);
+        assert_eq!(held.set(&keymap, UP, false), None);
+        assert_eq!(held.set(&keymap, w, false), Some((0x5, false)));
+    }
+
+    #[test]
+    fn test_remove_pad() {
+        let keymap = Keymap::default();
+        let mut held = Held::default();
+        held.set(&keymap, A, true);
+        held.set(&keymap, STICK_UP, true);
+        held.set(&keymap, Source::Key(Scancode::Num1), true);
+        assert_eq!(held.remove_pad(&keymap, 0), vec![(0x5, false)]);
+        assert_eq!(held.remove_pad(&keymap, 0), vec![]);
+        assert_eq!(held.remove_pad(&keymap, 1), vec![(0x2, false)]);
+    }
+}
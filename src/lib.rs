@@ -0,0 +1,19 @@
+pub mod asm;
+pub mod audio;
+pub mod cpu;
+pub mod disasm;
+pub mod fonts;
+pub mod machine;
+pub mod memory;
+pub mod movie;
+pub mod pacer;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod rom;
+pub mod screen;
+pub mod snapshot;
+pub mod state;
+
+pub use machine::Machine;
+pub use quirks::Quirks;
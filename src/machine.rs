@@ -0,0 +1,351 @@
+use crate::cpu::{CpuError, AUDIO_PATTERN_SIZE, CPU};
+use crate::fonts;
+use crate::memory::Memory;
+use crate::quirks::Quirks;
+use crate::rng::Rng;
+use crate::rom;
+use crate::state::{self, Reader, StateError, Writer};
+
+pub const TARGET_FPS: u32 = 60;
+pub const TARGET_MHZ: u32 = 540;
+// the default number of CPU cycles that occur before a refresh happens
+pub const CYCLES_PER_FRAME: u32 = TARGET_MHZ / TARGET_FPS;
+
+/// A headless Chip8 machine, owns the CPU along with its memory and screen.
+///
+/// Frontends drive it by calling `run_frame` once per refresh and reading
+/// back the framebuffer and sound state.
+#[derive(Clone, Debug)]
+pub struct Machine {
+    cpu: CPU,
+    /// identifies the loaded ROM in save states
+    rom_hash: u64,
+    /// the seed the random number generator started from, `None` unless it
+    /// was created with `with_seed`
+    seed: Option<u64>,
+    /// instructions run by `run_frame`
+    cycles_per_frame: u32,
+}
+
+impl Machine {
+    /// Create a machine with the fonts and `rom` loaded, fails if the ROM
+    /// doesn't fit in memory. Random numbers come from the thread RNG.
+    pub fn new(rom: &[u8], quirks: Quirks) -> Result<Self, CpuError> {
+        Self::with_rng(rom, quirks, Rng::Thread)
+    }
+
+    /// Like `new`, but seeds the random number generator with `seed` so runs
+    /// with the same input are reproducible.
+    pub fn with_seed(rom: &[u8], quirks: Quirks, seed: u64) -> Result<Self, CpuError> {
+        let mut machine = Self::with_rng(rom, quirks, Rng::seeded(seed))?;
+        machine.seed = Some(seed);
+        Ok(machine)
+    }
+
+    /// Like `new`, with random numbers drawn from `rng`.
+    pub fn with_rng(rom: &[u8], quirks: Quirks, rng: Rng) -> Result<Self, CpuError> {
+        let mut memory = if quirks.extended_memory {
+            Memory::extended()
+        } else {
+            Memory::new()
+        };
+        memory.load(&fonts::FONTSET, fonts::BASE_ADDRESS)?;
+        memory.load(&fonts::LARGE_FONTSET, fonts::LARGE_BASE_ADDRESS)?;
+        memory.load(rom, rom::BASE_ADDRESS)?;
+        Ok(Self {
+            cpu: CPU::new(memory, quirks, rng),
+            rom_hash: state::rom_hash(rom),
+            seed: None,
+            cycles_per_frame: CYCLES_PER_FRAME,
+        })
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    /// Hash of the loaded ROM, see `state::rom_hash`.
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    /// The seed the random number generator started from, `None` unless the
+    /// machine was created with `with_seed`.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// The number of instructions `run_frame` executes, `CYCLES_PER_FRAME`
+    /// unless changed with `set_cycles_per_frame`.
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    /// Change how many instructions a frame runs, programs written for faster
+    /// interpreters often need more than the default.
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.cycles_per_frame = cycles;
+    }
+
+    /// Reset the CPU and screen, leaving memory as it is. See `CPU::reset`.
+    pub fn soft_reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    /// Power cycle with `rom` loaded into fresh memory, which may have changed
+    /// since the machine was created. A seeded machine starts its random
+    /// numbers from the seed again, the instructions per frame and held keys
+    /// are kept. The machine is left untouched if the ROM doesn't fit.
+    pub fn hard_reset(&mut self, rom: &[u8]) -> Result<(), CpuError> {
+        let rng = self.seed.map_or(Rng::Thread, Rng::seeded);
+        let mut machine = Self::with_rng(rom, self.cpu.quirks(), rng)?;
+        machine.seed = self.seed;
+        machine.cycles_per_frame = self.cycles_per_frame;
+        for (key, pressed) in self.cpu.keys().iter().enumerate() {
+            machine.press_key(key as u8, *pressed);
+        }
+        *self = machine;
+        Ok(())
+    }
+
+    /// Serialize the full machine state, see `load_state`.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer::new(self.rom_hash);
+        self.cpu.write_state(&mut w);
+        w.finish()
+    }
+
+    /// Restore a state from `save_state`, the state has to have been saved
+    /// with the same ROM. The machine is left untouched if loading fails.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = Reader::new(data, self.rom_hash)?;
+        let mut cpu = self.cpu.clone();
+        cpu.read_state(&mut r)?;
+        r.finish()?;
+        self.cpu = cpu;
+        Ok(())
+    }
+
+    /// Execute a single instruction.
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        self.cpu.tick()
+    }
+
+    /// Tick the timers and execute a frame's worth of instructions.
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        self.run_frame_until(|_| false).map(|_| ())
+    }
+
+    /// Like `run_frame`, but `stop` is checked before every instruction and
+    /// the frame is cut short if it returns true. Returns whether it stopped.
+    pub fn run_frame_until<F>(&mut self, mut stop: F) -> Result<bool, CpuError>
+    where
+        F: FnMut(&CPU) -> bool,
+    {
+        self.cpu.tick_timers();
+        for _ in 0..self.cycles_per_frame {
+            if stop(&self.cpu) {
+                return Ok(true);
+            }
+            self.step()?;
+        }
+        Ok(false)
+    }
+
+    pub fn press_key(&mut self, key: u8, pressed: bool) {
+        self.cpu.press_key(key, pressed);
+    }
+
+    /// The screen pixels, stored row by row at the current resolution. Each
+    /// pixel is a bitmask of the XO-CHIP planes it is set in.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.cpu.screen_buffer()
+    }
+
+    /// The current screen resolution as (width, height), this changes when
+    /// SUPER-CHIP programs switch between lores and hires.
+    pub fn resolution(&self) -> (usize, usize) {
+        self.cpu.screen_resolution()
+    }
+
+    /// Whether the program has exited (SUPER-CHIP `00FD`).
+    pub fn has_exited(&self) -> bool {
+        self.cpu.has_exited()
+    }
+
+    pub fn sound_active(&self) -> bool {
+        self.cpu.is_sound_playing()
+    }
+
+    /// Frames left before the buzzer stops.
+    pub fn sound_timer(&self) -> u8 {
+        self.cpu.st()
+    }
+
+    /// The XO-CHIP audio pattern, `None` if the program hasn't loaded one.
+    pub fn audio_pattern(&self) -> Option<&[u8; AUDIO_PATTERN_SIZE]> {
+        self.cpu.audio_pattern()
+    }
+
+    /// The XO-CHIP audio pitch register.
+    pub fn pitch(&self) -> u8 {
+        self.cpu.pitch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen;
+
+    #[test]
+    fn test_loads_rom() {
+        // 6A02: LD VA, 0x02
+        let machine = Machine::new(&[0x6A, 0x02], Quirks::default()).unwrap();
+        assert_eq!(machine.framebuffer().len(), screen::SIZE);
+        assert!(!machine.sound_active());
+    }
+
+    #[test]
+    fn test_draws_font() {
+        // A000: LD I, 0x000 (font "0")
+        // D015: DRW V0, V1, 5
+        // 1204: JP 0x204
+        let mut machine =
+            Machine::new(&[0xA0, 0x00, 0xD0, 0x15, 0x12, 0x04], Quirks::default()).unwrap();
+        machine.run_frame().unwrap();
+        let buffer = machine.framebuffer();
+        // top row of "0" is 0xF0
+        assert_eq!(&buffer[0..8], &[1, 1, 1, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_run_frame_until() {
+        // 6A02: LD VA, 0x02
+        // 1202: JP 0x202
+        let mut machine = Machine::new(&[0x6A, 0x02, 0x12, 0x02], Quirks::default()).unwrap();
+        assert_eq!(machine.run_frame_until(|cpu| cpu.pc() == 0x202), Ok(true));
+        assert_eq!(machine.cpu().pc(), 0x202);
+        assert_eq!(machine.cpu().v()[0xA], 0x02);
+        assert_eq!(machine.run_frame_until(|cpu| cpu.pc() == 0x200), Ok(false));
+    }
+
+    #[test]
+    fn test_cycles_per_frame() {
+        // 7001: ADD V0, 0x01, repeated
+        let rom = [0x70, 0x01].repeat(40);
+        let mut machine = Machine::new(&rom, Quirks::default()).unwrap();
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu().v()[0x0], CYCLES_PER_FRAME as u8);
+        machine.set_cycles_per_frame(20);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu().v()[0x0], CYCLES_PER_FRAME as u8 + 20);
+    }
+
+    #[test]
+    fn test_reset() {
+        // 7001: ADD V0, 0x01
+        // 1202: JP 0x202
+        let mut machine =
+            Machine::with_seed(&[0x70, 0x01, 0x12, 0x02], Quirks::default(), 3).unwrap();
+        machine.set_cycles_per_frame(4);
+        machine.run_frame().unwrap();
+        machine.soft_reset();
+        assert_eq!(machine.cpu().v()[0x0], 0);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu().v()[0x0], 1);
+
+        // 7002: ADD V0, 0x02
+        machine.hard_reset(&[0x70, 0x02, 0x12, 0x02]).unwrap();
+        assert_eq!(machine.seed(), Some(3));
+        assert_eq!(machine.cycles_per_frame(), 4);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu().v()[0x0], 2);
+        assert_eq!(
+            machine.rom_hash(),
+            state::rom_hash(&[0x70, 0x02, 0x12, 0x02])
+        );
+
+        let rom = [0; 0x1000 - 0x200 + 1];
+        assert!(machine.hard_reset(&rom).is_err());
+        assert_eq!(machine.cpu().v()[0x0], 2);
+    }
+
+    #[test]
+    fn test_rom_too_large() {
+        let rom = [0; 0x1000 - 0x200 + 1];
+        assert_eq!(
+            Machine::new(&rom, Quirks::default()).unwrap_err(),
+            CpuError::MemoryOutOfBounds { addr: 0xFFF }
+        );
+        assert!(Machine::new(&rom, Quirks::XO_CHIP).is_ok());
+        let rom = [0; 0x10000 - 0x200 + 1];
+        assert_eq!(
+            Machine::new(&rom, Quirks::XO_CHIP).unwrap_err(),
+            CpuError::MemoryOutOfBounds { addr: 0xFFFF }
+        );
+    }
+
+    #[test]
+    fn test_invalid_opcode() {
+        let mut machine = Machine::new(&[0xFF, 0xFF], Quirks::default()).unwrap();
+        assert_eq!(
+            machine.step(),
+            Err(CpuError::InvalidOpcode {
+                pc: 0x200,
+                opcode: 0xFFFF
+            })
+        );
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        // 00EE: RET
+        let mut machine = Machine::new(&[0x00, 0xEE], Quirks::default()).unwrap();
+        assert_eq!(machine.step(), Err(CpuError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // 2200: CALL 0x200
+        let mut machine = Machine::new(&[0x22, 0x00], Quirks::default()).unwrap();
+        for _ in 0..16 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.step(), Err(CpuError::StackOverflow));
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        // 6A02: LD VA, 0x02
+        // A000: LD I, 0x000
+        // D015: DRW V0, V1, 5
+        // 7A01: ADD VA, 0x01
+        let rom = [0x6A, 0x02, 0xA0, 0x00, 0xD0, 0x15, 0x7A, 0x01];
+        let mut machine = Machine::new(&rom, Quirks::default()).unwrap();
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        let state = machine.save_state();
+        machine.step().unwrap();
+        assert_eq!(machine.cpu().v()[0xA], 0x03);
+
+        machine.load_state(&state).unwrap();
+        assert_eq!(machine.cpu().v()[0xA], 0x02);
+        assert_eq!(machine.cpu().pc(), 0x206);
+        assert_eq!(machine.framebuffer()[0], 1);
+        assert_eq!(machine.save_state(), state);
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let mut machine = Machine::new(&[0x6A, 0x02], Quirks::default()).unwrap();
+        let state = machine.save_state();
+        let mut other = Machine::new(&[0x6A, 0x03], Quirks::default()).unwrap();
+        assert_eq!(other.load_state(&state), Err(StateError::RomMismatch));
+        assert_eq!(
+            machine.load_state(&state[..state.len() - 1]),
+            Err(StateError::InvalidFormat)
+        );
+    }
+}
@@ -1,35 +1,36 @@
 mod buzzer;
-mod cpu;
-mod fonts;
-mod memory;
-mod rom;
-mod screen;
+mod capture;
+mod debugger;
+mod frontend;
+mod gamepad;
+mod keymap;
+mod terminal;
 
-use clap::{Args, Parser};
+use buzzer::Buzzer;
+use chip8::audio::{Tone, Waveform};
+use chip8::machine::{CYCLES_PER_FRAME, TARGET_FPS};
+use chip8::movie::{Movie, Player, Recorder};
+use chip8::rewind::Rewind;
+use chip8::state;
+use chip8::{asm, disasm, rom, screen, snapshot, Machine, Quirks};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use keymap::{Held, Keymap, Source};
 use sdl2::VideoSubsystem;
 use sdl2::event::Event;
-use sdl2::keyboard::Scancode;
+use sdl2::keyboard::{Mod, Scancode};
 use sdl2::pixels::{Color, PixelFormat, PixelFormatEnum};
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-const TARGET_FPS: u32 = 60;
-const TARGET_MHZ: u32 = 540;
-// the number of CPU cycles that occur before a refresh happens
-const CYCLES_PER_REFRESH: u32 = TARGET_MHZ / TARGET_FPS;
-const REFRESH_PER_SECOND: f32 = 1. / TARGET_FPS as f32;
+/// Speeds, as a percentage, that page up and down step through.
+const SPEEDS: &[u32] = &[10, 25, 50, 75, 100, 150, 200, 300, 400, 800];
 
-fn timed<F>(mut f: F) -> Duration
-where
-    F: FnMut() -> (),
-{
-    let start = Instant::now();
-    f();
-    start.elapsed()
-}
+/// Speeds while tab (fast-forward) or the backquote key (slow motion) is held.
+const FAST_FORWARD_SPEED: u32 = 400;
+const SLOW_MOTION_SPEED: u32 = 25;
 
 fn init_graphics(
     config: &Config,
@@ -48,7 +49,11 @@ fn init_graphics(
         .expect("window creation failed");
 
     // init rendering
-    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas();
+    if config.vsync {
+        canvas = canvas.present_vsync();
+    }
+    let mut canvas = canvas.build().map_err(|e| e.to_string())?;
     canvas.set_draw_color(Color::RGB(0xFF, 0xFF, 0xFF));
     Ok(canvas)
 }
@@ -58,46 +63,109 @@ fn clear_graphics(config: &Config, canvas: &mut Canvas<Window>) {
     canvas.clear();
 }
 
-fn draw_graphics(config: &Config, canvas: &mut Canvas<Window>, buffer: &[bool]) {
-    canvas.set_draw_color(config.fg.to_sdl_color());
+fn draw_graphics(
+    config: &Config,
+    canvas: &mut Canvas<Window>,
+    buffer: &[u8],
+    (width, height): (usize, usize),
+) {
+    // the window is sized for lores, hires pixels are drawn at half the scale
+    let window_width = screen::WIDTH as u32 * config.scale;
+    let window_height = screen::HEIGHT as u32 * config.scale;
+    let (width, height) = (width as u32, height as u32);
+    // indexed by the planes a pixel is set in
+    let palette = [
+        config.bg.to_sdl_color(),
+        config.fg.to_sdl_color(),
+        config.fg2.to_sdl_color(),
+        config.fg3.to_sdl_color(),
+    ];
     for (i, pixel) in buffer.iter().enumerate() {
-        if *pixel {
-            let x = (i % screen::WIDTH) as u32;
-            let y = (i / screen::WIDTH) as u32;
-            let rect = Rect::new(
-                (x * config.scale) as i32,
-                (y * config.scale) as i32,
-                config.scale,
-                config.scale,);
+        if *pixel != 0 {
+            canvas.set_draw_color(palette[*pixel as usize]);
+            let x = i as u32 % width;
+            let y = i as u32 / width;
+            // compute both edges so that odd scales don't leave gaps
+            let left = x * window_width / width;
+            let top = y * window_height / height;
+            let right = (x + 1) * window_width / width;
+            let bottom = (y + 1) * window_height / height;
+            let rect = Rect::new(left as i32, top as i32, right - left, bottom - top);
             canvas.fill_rect(rect).expect("fill_rect failed");
         }
     }
     canvas.present();
 }
 
-fn scancode_to_key(scancode: Scancode) -> Option<u8> {
+/// Keys the emulator handles itself, these can't be bound to the keypad.
+const HOTKEYS: &[Scancode] = {
     use Scancode::*;
+    &[
+        Escape, Backspace, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, Minus, Equals,
+        LeftBracket, RightBracket, PageUp, PageDown, Home, Tab, Grave, Insert, End, Delete,
+    ]
+};
+
+/// The tone after pressing a buzzer hotkey, `None` for other keys.
+fn tone_change(tone: Tone, scancode: Scancode) -> Option<Tone> {
+    let semitone = 2f32.powf(1. / 12.);
     match scancode {
-        Num1 => Some(0x1),
-        Num2 => Some(0x2),
-        Num3 => Some(0x3),
-        Num4 => Some(0xC),
-        Q => Some(0x4),
-        W => Some(0x5),
-        E => Some(0x6),
-        R => Some(0xD),
-        A => Some(0x7),
-        S => Some(0x8),
-        D => Some(0x9),
-        F => Some(0xE),
-        Z => Some(0xA),
-        X => Some(0x0),
-        C => Some(0xB),
-        V => Some(0xF),
+        Scancode::F10 => Some(Tone { waveform: tone.waveform.next(), ..tone }),
+        Scancode::Minus => Some(Tone { volume: tone.volume - 0.05, ..tone }),
+        Scancode::Equals => Some(Tone { volume: tone.volume + 0.05, ..tone }),
+        Scancode::LeftBracket => Some(Tone { frequency: tone.frequency / semitone, ..tone }),
+        Scancode::RightBracket => Some(Tone { frequency: tone.frequency * semitone, ..tone }),
         _ => None,
     }
 }
 
+/// The next speed up from `speed`, or down if `up` is false, staying at the
+/// fastest or slowest.
+fn step_speed(speed: u32, up: bool) -> u32 {
+    let next = if up {
+        SPEEDS.iter().find(|s| **s > speed)
+    } else {
+        SPEEDS.iter().rev().find(|s| **s < speed)
+    };
+    next.copied().unwrap_or(speed)
+}
+
+/// The save state slot for a function key, F1 to F9.
+fn scancode_to_slot(scancode: Scancode) -> Option<u8> {
+    use Scancode::*;
+    [F1, F2, F3, F4, F5, F6, F7, F8, F9]
+        .iter()
+        .position(|s| *s == scancode)
+        .map(|i| i as u8 + 1)
+}
+
+/// Save states are stored next to the ROM, e.g. `roms/GAME.1.state`.
+fn state_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("{}.state", slot))
+}
+
+fn save_state(machine: &Machine, path: &Path) -> Result<(), String> {
+    std::fs::write(path, machine.save_state())
+        .map_err(|e| format!("failed to save state to {}: {}", path.display(), e))
+}
+
+fn load_state(machine: &mut Machine, path: &Path) -> Result<(), String> {
+    let context = |e: String| format!("failed to load state from {}: {}", path.display(), e);
+    let data = std::fs::read(path).map_err(|e| context(e.to_string()))?;
+    machine.load_state(&data).map_err(|e| context(e.to_string()))
+}
+
+fn save_movie(movie: &Movie, path: &Path) -> Result<(), String> {
+    std::fs::write(path, movie.save())
+        .map_err(|e| format!("failed to save movie to {}: {}", path.display(), e))
+}
+
+fn load_movie(rom: &[u8], path: &Path) -> Result<Movie, String> {
+    let context = |e: String| format!("failed to load movie from {}: {}", path.display(), e);
+    let data = std::fs::read(path).map_err(|e| context(e.to_string()))?;
+    Movie::load(&data, state::rom_hash(rom)).map_err(|e| context(e.to_string()))
+}
+
 #[derive(Clone, Debug)]
 struct ColorArg(u32);
 
@@ -108,6 +176,11 @@ impl ColorArg {
             .expect("invalid format provided to PixelFormat");
         Color::from_u32(&format, self.0)
     }
+
+    pub fn to_rgb(&self) -> [u8; 3] {
+        let [_, r, g, b] = self.0.to_be_bytes();
+        [r, g, b]
+    }
 }
 
 impl std::str::FromStr for ColorArg {
@@ -124,6 +197,51 @@ impl std::fmt::Display for ColorArg {
     }
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum QuirksArg {
+    /// COSMAC VIP
+    Vip,
+    /// CHIP-48
+    Chip48,
+    /// SUPER-CHIP
+    Schip,
+    /// XO-CHIP
+    Xochip,
+}
+
+impl QuirksArg {
+    fn to_quirks(self) -> Quirks {
+        match self {
+            QuirksArg::Vip => Quirks::COSMAC_VIP,
+            QuirksArg::Chip48 => Quirks::CHIP48,
+            QuirksArg::Schip => Quirks::SUPER_CHIP,
+            QuirksArg::Xochip => Quirks::XO_CHIP,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum WaveformArg {
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+    /// random levels, changing once per period of the tone
+    Noise,
+}
+
+impl WaveformArg {
+    fn to_waveform(self) -> Waveform {
+        match self {
+            WaveformArg::Square => Waveform::Square,
+            WaveformArg::Sine => Waveform::Sine,
+            WaveformArg::Triangle => Waveform::Triangle,
+            WaveformArg::Sawtooth => Waveform::Sawtooth,
+            WaveformArg::Noise => Waveform::Noise,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Args)]
 struct Config {
     #[arg(
@@ -138,91 +256,784 @@ struct Config {
         default_value_t = ColorArg(0x000000),
     )]
     bg: ColorArg,
+    #[arg(
+        long,
+        help = "Colour of pixels only in the second XO-CHIP plane in hex format",
+        default_value_t = ColorArg(0xFF0000),
+    )]
+    fg2: ColorArg,
+    #[arg(
+        long,
+        help = "Colour of pixels in both XO-CHIP planes in hex format",
+        default_value_t = ColorArg(0xFFFF00),
+    )]
+    fg3: ColorArg,
     #[arg(short, long, default_value_t = 20)]
     scale: u32,
+    #[arg(long, help = "Wait for the display's vertical sync when drawing to avoid tearing")]
+    vsync: bool,
+    #[arg(long, help = "Emulate the quirks of another interpreter")]
+    quirks: Option<QuirksArg>,
+    #[arg(
+        long,
+        help = "Instructions run per frame, at 60 frames a second",
+        default_value_t = CYCLES_PER_FRAME,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    cycles_per_frame: u32,
+    #[arg(long, help = "Shape of the buzzer tone", value_enum, default_value_t = WaveformArg::Square)]
+    waveform: WaveformArg,
+    #[arg(
+        long,
+        help = "Frequency of the buzzer tone in Hz",
+        default_value_t = 150,
+        value_parser = clap::value_parser!(u32).range(20..=20000)
+    )]
+    tone: u32,
+    #[arg(
+        long,
+        help = "Buzzer volume as a percentage",
+        default_value_t = 25,
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    volume: u8,
+    #[arg(
+        long,
+        help = "Name of the audio device to play the buzzer on, defaults to the system's. \
+                Shift+F10 moves to the next device while running"
+    )]
+    audio_device: Option<String>,
+    #[arg(
+        long,
+        help = "Keypad layout, a preset (qwerty or numpad, bound by key position whatever the \
+                keyboard layout) or the path to a keymap file, defaults to GAME.keymap next to \
+                the ROM if there is one"
+    )]
+    keymap: Option<String>,
 }
 
-#[derive(Debug, Parser)]
-struct Cli {
+impl Config {
+    fn tone(&self) -> Tone {
+        Tone {
+            waveform: self.waveform.to_waveform(),
+            frequency: self.tone as f32,
+            volume: self.volume as f32 / 100.,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DumpFormat {
+    /// the screen as text, one line per row
+    Ascii,
+    /// a hash of the screen, for comparing against a known good run
+    Hash,
+    /// a plain PBM image
+    Pbm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Frontend {
+    /// a window, with sound through the sound card
+    Sdl,
+    /// text in the terminal, for running over SSH
+    Terminal,
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
     #[arg(help = "Path to a Chip8 ROM")]
     rom_path: PathBuf,
     #[command(flatten)]
     config: Config,
+    #[arg(long, help = "Start paused with a debugger on the terminal")]
+    debug: bool,
+    #[arg(long, help = "Path to a save state to load on startup")]
+    load_state: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Record keypad input to a movie file for replaying later",
+        conflicts_with_all = ["debug", "load_state", "replay"]
+    )]
+    record_movie: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Replay a movie recorded with --record-movie",
+        conflicts_with_all = ["debug", "load_state"]
+    )]
+    replay: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Seed the random number generator to make runs reproducible",
+        conflicts_with = "replay"
+    )]
+    seed: Option<u64>,
+    #[arg(
+        long,
+        help = "Run without a window or audio for --frames frames, then dump the screen",
+        requires = "frames",
+        conflicts_with_all = ["debug", "record_movie"]
+    )]
+    headless: bool,
+    #[arg(long, help = "Number of frames to run in headless mode", requires = "headless")]
+    frames: Option<u32>,
+    #[arg(
+        long,
+        help = "How the screen is dumped in headless mode",
+        value_enum,
+        default_value_t = DumpFormat::Ascii
+    )]
+    dump: DumpFormat,
+    #[arg(
+        short,
+        long,
+        help = "Path to write the headless dump to instead of stdout",
+        requires = "headless"
+    )]
+    output: Option<PathBuf>,
+    #[arg(long, help = "Save a PNG screenshot of the last frame to this path on exit")]
+    screenshot_on_exit: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Record the screen to an animated GIF, or a Y4M video if the path ends in .y4m"
+    )]
+    record: Option<PathBuf>,
+    #[arg(long, help = "Record the buzzer to a WAV file, in step with the emulation")]
+    record_audio: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Capture screenshots and recordings at the screen's own resolution rather than the window's"
+    )]
+    capture_native: bool,
+    #[arg(
+        long,
+        help = "Seconds of history kept for rewinding with backspace, 0 disables [default: 10]"
+    )]
+    rewind_seconds: Option<u32>,
+    #[arg(
+        long,
+        help = "Run as fast as possible rather than at 60 frames a second, for benchmarking",
+        conflicts_with = "headless"
+    )]
+    uncapped: bool,
+    #[arg(
+        long,
+        help = "Where to show the screen and read the keyboard",
+        value_enum,
+        default_value_t = Frontend::Sdl
+    )]
+    frontend: Frontend,
+    #[arg(
+        long,
+        help = "How the terminal frontend draws pixels",
+        value_enum,
+        default_value_t = terminal::Cells::HalfBlock
+    )]
+    cells: terminal::Cells,
+}
+
+#[derive(Debug, Args)]
+struct DisasmArgs {
+    #[arg(help = "Path to a Chip8 ROM")]
+    rom_path: PathBuf,
+    #[arg(long, help = "Follow jumps and calls to separate code from data")]
+    follow: bool,
+}
+
+#[derive(Debug, Args)]
+struct AsmArgs {
+    #[arg(help = "Path to an Octo source file")]
+    source_path: PathBuf,
+    #[arg(
+        short,
+        long,
+        help = "Path to write the ROM to, defaults to the source path with a .ch8 extension"
+    )]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run a ROM
+    Run(Box<RunArgs>),
+    /// Print a disassembly of a ROM
+    Disasm(DisasmArgs),
+    /// Assemble Octo source into a ROM
+    Asm(AsmArgs),
+}
+
+#[derive(Debug, Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 }
 
 fn main() -> Result<(), String> {
-    let args = Cli::parse();
+    match Cli::parse().command {
+        Command::Run(args) => run(*args),
+        Command::Disasm(args) => disassemble(args),
+        Command::Asm(args) => assemble(args),
+    }
+}
+
+fn assemble(args: AsmArgs) -> Result<(), String> {
+    let source = std::fs::read_to_string(&args.source_path).map_err(|e| e.to_string())?;
+    let rom = asm::assemble(&source)
+        .map_err(|e| format!("{}:{}", args.source_path.display(), e))?;
+    let output = args
+        .output
+        .unwrap_or_else(|| args.source_path.with_extension("ch8"));
+    std::fs::write(&output, rom).map_err(|e| e.to_string())
+}
+
+fn disassemble(args: DisasmArgs) -> Result<(), String> {
+    let rom = rom::load(&args.rom_path).map_err(|e| e.to_string())?;
+    for line in disasm::disassemble(&rom, args.follow) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn run(args: RunArgs) -> Result<(), String> {
     let config = args.config;
     let rom = rom::load(&args.rom_path).map_err(|e| e.to_string())?;
+    let keymap = Keymap::for_rom(config.keymap.as_deref(), &args.rom_path)?;
+    keymap.check_reserved(HOTKEYS)?;
+    let mut player = match &args.replay {
+        Some(path) => Some(Player::new(load_movie(&rom, path)?)),
+        None => None,
+    };
+    let quirks = config.quirks.map(QuirksArg::to_quirks);
+    let quirks = match (&player, quirks) {
+        (Some(player), Some(quirks)) if quirks != player.movie().quirks() => {
+            return Err("--quirks differs from the quirks the movie was recorded with".to_string());
+        }
+        // a movie only replays correctly with the quirks it was recorded with
+        (Some(player), _) => player.movie().quirks(),
+        (None, quirks) => quirks.unwrap_or_default(),
+    };
+    let seed = match (&player, args.seed) {
+        (Some(player), _) => Some(player.movie().seed()),
+        // movies can only be replayed with a seeded machine
+        (None, None) if args.record_movie.is_some() => Some(rand::random()),
+        (None, seed) => seed,
+    };
+    let mut machine = match seed {
+        Some(seed) => Machine::with_seed(&rom, quirks, seed),
+        None => Machine::new(&rom, quirks),
+    }
+    .map_err(|e| e.to_string())?;
+    // a movie only replays correctly at the speed it was recorded at
+    machine.set_cycles_per_frame(match &player {
+        Some(player) => player.movie().cycles_per_frame(),
+        None => config.cycles_per_frame,
+    });
+    if let Some(path) = &args.load_state {
+        load_state(&mut machine, path)?;
+    }
+    let mut video = match &args.record {
+        Some(path) => Some(start_recording(&config, &machine, args.capture_native, path)?),
+        None => None,
+    };
+    let mut audio = match &args.record_audio {
+        Some(path) => Some(capture::AudioRecorder::create(path, config.tone())?),
+        None => None,
+    };
+    if args.headless {
+        let frames = args.frames.unwrap_or_default();
+        let result =
+            run_headless(&mut machine, player, video, audio, frames, args.dump, args.output);
+        if let Some(path) = &args.screenshot_on_exit {
+            screenshot(&config, &machine, args.capture_native, path)?;
+        }
+        return result;
+    }
+    if args.frontend == Frontend::Terminal {
+        let unsupported = [
+            (args.debug, "--debug"),
+            (args.record_movie.is_some(), "--record-movie"),
+            (args.replay.is_some(), "--replay"),
+            (args.record.is_some(), "--record"),
+            (args.record_audio.is_some(), "--record-audio"),
+            (args.uncapped, "--uncapped"),
+            (args.rewind_seconds.is_some(), "--rewind-seconds"),
+            (config.vsync, "--vsync"),
+        ];
+        if let Some((_, flag)) = unsupported.iter().find(|(used, _)| *used) {
+            return Err(format!("{} isn't supported by the terminal frontend", flag));
+        }
+        let palette = palette(&config);
+        let result = terminal::run(&mut machine, &keymap, &palette, args.cells, &args.rom_path);
+        if let Some(path) = &args.screenshot_on_exit {
+            screenshot(&config, &machine, args.capture_native, path)?;
+        }
+        return result;
+    }
 
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
     let audio_subsystem = sdl_context.audio()?;
+    let mut gamepads = gamepad::Gamepads::new(sdl_context.game_controller()?);
 
     let mut canvas = init_graphics(&config, video_subsystem)?;
 
     // init audio
-    let buzzer = buzzer::Buzzer::new(audio_subsystem)?;
+    let mut tone = config.tone();
+    let mut buzzer = Buzzer::new(audio_subsystem, config.audio_device.as_deref(), tone)?;
 
-    // load emulator components
-    let mut memory = memory::Memory::new();
-    memory.load(&fonts::FONTSET, fonts::BASE_ADDRESS);
-    memory.load(&rom, rom::BASE_ADDRESS);
-
-    let mut cpu = cpu::CPU::new(memory);
+    let mut recorder = args
+        .record_movie
+        .as_ref()
+        .and_then(|_| Recorder::new(&machine));
+    // movies run from power on, jumping around in time would desync them
+    let movie_active = recorder.is_some() || player.is_some();
+    let mut debugger = args.debug.then(debugger::Debugger::new);
+    let rewind_seconds = if movie_active { 0 } else { args.rewind_seconds.unwrap_or(10) };
+    let mut rewind = Rewind::new((rewind_seconds * TARGET_FPS) as usize);
+    let mut rewinding = false;
+    // paused with insert, end then runs a single frame
+    let mut pause = false;
+    let mut frame_advance = false;
+    let mut error = None;
+    let mut pacer = frontend::pacer();
+    let mut speed = 100;
+    let mut fast_forward = false;
+    let mut slow_motion = false;
+    let mut last_update = Instant::now();
+    // frames run since the title last showed the speed
+    let mut title_frames = 0;
+    let mut title_updated = last_update;
+    // keyboard keys and controller inputs held down
+    let mut held = Held::default();
     // main loop
     let mut event_pump = sdl_context.event_pump()?;
     'running: loop {
         // handle events
         for event in event_pump.poll_iter() {
+            for (k, pressed) in gamepads.handle_event(&keymap, &mut held, &event) {
+                press_key(&mut machine, recorder.as_mut(), &player, k, pressed);
+            }
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     scancode: Some(Scancode::Escape),
                     ..
                 } => break 'running,
+                // hold backspace to rewind
+                Event::KeyDown {
+                    scancode: Some(Scancode::Backspace),
+                    ..
+                } => rewinding = true,
+                Event::KeyUp {
+                    scancode: Some(Scancode::Backspace),
+                    ..
+                } => rewinding = false,
+                Event::KeyDown {
+                    scancode: Some(Scancode::Insert),
+                    repeat: false,
+                    ..
+                } => {
+                    pause = !pause;
+                    println!("{}", if pause { "paused" } else { "resumed" });
+                }
+                Event::KeyDown {
+                    scancode: Some(Scancode::End),
+                    ..
+                } if pause => frame_advance = true,
+                // delete resets the CPU and screen, holding shift reloads the
+                // ROM from disk too
+                Event::KeyDown {
+                    scancode: Some(Scancode::Delete),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    let hard = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    let result = if movie_active {
+                        Err("can't reset while recording or replaying a movie".to_string())
+                    } else {
+                        frontend::reset(&mut machine, &args.rom_path, hard)
+                    };
+                    // the history belongs to the ROM as it was
+                    if hard && result.is_ok() {
+                        rewind.clear();
+                    }
+                    match result {
+                        Ok(message) => println!("{}", message),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                // hold tab to fast-forward and backquote for slow motion
+                Event::KeyDown {
+                    scancode: Some(Scancode::Tab),
+                    ..
+                } => fast_forward = true,
+                Event::KeyUp {
+                    scancode: Some(Scancode::Tab),
+                    ..
+                } => fast_forward = false,
+                Event::KeyDown {
+                    scancode: Some(Scancode::Grave),
+                    ..
+                } => slow_motion = true,
+                Event::KeyUp {
+                    scancode: Some(Scancode::Grave),
+                    ..
+                } => slow_motion = false,
+                // page up and down change the speed, home goes back to normal
+                Event::KeyDown {
+                    scancode:
+                        Some(scancode @ (Scancode::PageUp | Scancode::PageDown | Scancode::Home)),
+                    ..
+                } => {
+                    speed = match scancode {
+                        Scancode::Home => 100,
+                        _ => step_speed(speed, scancode == Scancode::PageUp),
+                    };
+                    println!("speed: {}%", speed);
+                }
+                Event::KeyDown {
+                    scancode: Some(Scancode::F12),
+                    repeat: false,
+                    ..
+                } => {
+                    let path = capture::screenshot_path(&args.rom_path);
+                    match screenshot(&config, &machine, args.capture_native, &path) {
+                        Ok(()) => println!("saved screenshot to {}", path.display()),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                // F11 starts and stops recording
+                Event::KeyDown {
+                    scancode: Some(Scancode::F11),
+                    repeat: false,
+                    ..
+                } => {
+                    let result = match video.take() {
+                        Some(v) => {
+                            let path = v.path().to_path_buf();
+                            v.finish().map(|_| format!("saved recording to {}", path.display()))
+                        }
+                        None => {
+                            let path = capture::recording_path(&args.rom_path);
+                            start_recording(&config, &machine, args.capture_native, &path).map(
+                                |v| {
+                                    video = Some(v);
+                                    format!("recording to {}", path.display())
+                                },
+                            )
+                        }
+                    };
+                    match result {
+                        Ok(message) => println!("{}", message),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                // shift+F10 moves the buzzer to the next audio device
+                Event::KeyDown {
+                    scancode: Some(Scancode::F10),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                    match buzzer.next_device() {
+                        Ok(name) => println!("audio device: {}", name),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                // F10 cycles the waveform, -/= change the volume and [/] the
+                // tone's frequency by a semitone
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    ..
+                } if tone_change(tone, scancode).is_some() => {
+                    tone = tone_change(tone, scancode).unwrap().clamped();
+                    buzzer.set_tone(tone);
+                    if let Some(audio) = audio.as_mut() {
+                        audio.set_tone(tone);
+                    }
+                    println!(
+                        "buzzer: {} {:.0} Hz at {:.0}%",
+                        tone.waveform,
+                        tone.frequency,
+                        tone.volume * 100.
+                    );
+                }
+                // F1-F9 load a save state, holding shift saves instead
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if scancode_to_slot(scancode).is_some() => {
+                    let slot = scancode_to_slot(scancode).unwrap();
+                    let path = state_path(&args.rom_path, slot);
+                    let result = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        save_state(&machine, &path).map(|_| "saved")
+                    } else if movie_active {
+                        Err("can't load states while recording or replaying a movie".to_string())
+                    } else {
+                        load_state(&mut machine, &path).map(|_| "loaded")
+                    };
+                    match result {
+                        Ok(action) => println!("{} state {}", action, slot),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
                 Event::KeyDown {
                     scancode: Some(scancode),
                     ..
                 } => {
-                    if let Some(k) = scancode_to_key(scancode) {
-                        cpu.press_key(k, true);
+                    if let Some((k, pressed)) = held.set(&keymap, Source::Key(scancode), true) {
+                        press_key(&mut machine, recorder.as_mut(), &player, k, pressed);
                     }
                 }
                 Event::KeyUp {
                     scancode: Some(scancode),
                     ..
                 } => {
-                    if let Some(k) = scancode_to_key(scancode) {
-                        cpu.press_key(k, false);
+                    if let Some((k, pressed)) = held.set(&keymap, Source::Key(scancode), false) {
+                        press_key(&mut machine, recorder.as_mut(), &player, k, pressed);
                     }
                 }
                 _ => {}
             }
         }
-        clear_graphics(&config, &mut canvas);
-
-        // timers
-        cpu.tick_timers();
-        // cpu tick
-        let elapsed = timed(|| {
-            for _ in 0..=CYCLES_PER_REFRESH {
-                cpu.tick();
+        if let Some(debugger) = debugger.as_mut() {
+            if !debugger.poll(&mut machine) {
+                break 'running;
             }
+        }
+        pacer.set_speed(if fast_forward {
+            FAST_FORWARD_SPEED
+        } else if slow_motion {
+            SLOW_MOTION_SPEED
+        } else {
+            speed
         });
-        // audio
-        if cpu.is_sound_playing() {
-            buzzer.play();
+        // run however many frames are due, normally one per update, or as
+        // many as fit in an update when uncapped
+        let now = Instant::now();
+        let due = pacer.advance(now - last_update);
+        let frames = if pause {
+            frame_advance as u32
+        } else if args.uncapped {
+            u32::MAX
         } else {
-            buzzer.pause();
+            due
+        };
+        frame_advance = false;
+        last_update = now;
+        for _ in 0..frames {
+            let result = match debugger.as_mut() {
+                _ if rewinding => {
+                    if let Some(state) = rewind.pop() {
+                        machine
+                            .load_state(state)
+                            .expect("rewind state should match the ROM");
+                    }
+                    Ok(())
+                }
+                // the debugger reports errors itself and pauses
+                Some(debugger) => {
+                    debugger.run_frame(&mut machine);
+                    Ok(())
+                }
+                None => match (recorder.as_mut(), player.as_mut()) {
+                    (Some(recorder), _) => recorder.run_frame(&mut machine),
+                    (_, Some(p)) if !p.is_finished() => p.run_frame(&mut machine),
+                    _ => machine.run_frame(),
+                },
+            };
+            if player.as_ref().is_some_and(Player::is_finished) {
+                println!("replay finished");
+                player = None;
+            }
+            // halt on a bad ROM rather than crashing, still saving the movie and
+            // screenshot so the crash can be reproduced
+            if let Err(e) = result {
+                error = Some(format!("CPU error: {}", e));
+                break 'running;
+            }
+            let stopped = debugger.as_ref().is_some_and(|d| d.is_paused());
+            let paused = rewinding || stopped;
+            if !paused {
+                rewind.push(machine.save_state());
+            }
+            // rewinding is recorded too, only the debugger stops time
+            if let Some(v) = video.as_mut().filter(|_| !stopped) {
+                if let Err(e) = v.add_frame(machine.framebuffer(), machine.resolution()) {
+                    eprintln!("{}", e);
+                    video = None;
+                }
+            }
+            if let Some(a) = audio.as_mut().filter(|_| !stopped) {
+                if let Err(e) = a.add_frame(&machine, paused) {
+                    eprintln!("{}", e);
+                    audio = None;
+                }
+            }
+            title_frames += 1;
+            if machine.has_exited() {
+                break;
+            }
+            if args.uncapped && now.elapsed() >= Duration::from_secs(1) / TARGET_FPS {
+                break;
+            }
+        }
+        // show the speed actually reached, once a second
+        let since_title = title_updated.elapsed();
+        if since_title >= Duration::from_secs(1) {
+            let expected = since_title.as_secs_f64() * TARGET_FPS as f64;
+            let percent = title_frames as f64 * 100. / expected;
+            let title = if pause {
+                "Chip8 - paused".to_string()
+            } else {
+                format!("Chip8 - {:.0}%", percent)
+            };
+            canvas.window_mut().set_title(&title).map_err(|e| e.to_string())?;
+            title_frames = 0;
+            title_updated = Instant::now();
         }
+        // audio
+        let paused = pause || rewinding || debugger.as_ref().is_some_and(|d| d.is_paused());
+        buzzer.update(&machine, paused);
 
-        draw_graphics(&config, &mut canvas, cpu.screen_buffer());
+        clear_graphics(&config, &mut canvas);
+        draw_graphics(
+            &config,
+            &mut canvas,
+            machine.framebuffer(),
+            machine.resolution(),
+        );
 
-        // wait for next iteration
-        let rps = Duration::from_secs_f32(REFRESH_PER_SECOND);
-        std::thread::sleep(rps - elapsed);
+        if machine.has_exited() {
+            break 'running;
+        }
+
+        // with vsync presenting the frame waits for the display instead
+        if !config.vsync && !args.uncapped {
+            std::thread::sleep(pacer.until_next_frame());
+        }
     }
 
-    Ok(())
+    if let (Some(recorder), Some(path)) = (&recorder, &args.record_movie) {
+        save_movie(recorder.movie(), path)?;
+    }
+    if let Some(path) = &args.screenshot_on_exit {
+        screenshot(&config, &machine, args.capture_native, path)?;
+    }
+    if let Some(video) = video {
+        video.finish()?;
+    }
+    if let Some(audio) = audio {
+        audio.finish()?;
+    }
+    error.map_or(Ok(()), Err)
+}
+
+/// Colours indexed by the planes a pixel is set in.
+fn palette(config: &Config) -> [[u8; 3]; 4] {
+    [
+        config.bg.to_rgb(),
+        config.fg.to_rgb(),
+        config.fg2.to_rgb(),
+        config.fg3.to_rgb(),
+    ]
+}
+
+/// Captures are either the size of the window or the screen's own resolution.
+fn capture_size(config: &Config, machine: &Machine, native: bool) -> (usize, usize) {
+    if native {
+        machine.resolution()
+    } else {
+        let scale = config.scale as usize;
+        (screen::WIDTH * scale, screen::HEIGHT * scale)
+    }
+}
+
+/// Save the screen to a PNG.
+fn screenshot(config: &Config, machine: &Machine, native: bool, path: &Path) -> Result<(), String> {
+    let size = capture_size(config, machine, native);
+    let rgb = snapshot::rgb(
+        machine.framebuffer(),
+        machine.resolution(),
+        &palette(config),
+        size,
+    );
+    capture::write_png(path, &rgb, size)
+}
+
+fn start_recording(
+    config: &Config,
+    machine: &Machine,
+    native: bool,
+    path: &Path,
+) -> Result<capture::VideoRecorder, String> {
+    let size = capture_size(config, machine, native);
+    capture::VideoRecorder::create(path, size, &palette(config))
+}
+
+/// Run `frames` frames without SDL, replaying `player` and recording to
+/// `video` if given, then dump the screen. The screen is still dumped if the
+/// CPU errors so the failure can be inspected.
+fn run_headless(
+    machine: &mut Machine,
+    mut player: Option<Player>,
+    mut video: Option<capture::VideoRecorder>,
+    mut audio: Option<capture::AudioRecorder>,
+    frames: u32,
+    format: DumpFormat,
+    output: Option<PathBuf>,
+) -> Result<(), String> {
+    let mut result = Ok(());
+    for _ in 0..frames {
+        result = match player.as_mut() {
+            Some(p) if !p.is_finished() => p.run_frame(machine),
+            _ => machine.run_frame(),
+        };
+        if let Some(video) = video.as_mut() {
+            video.add_frame(machine.framebuffer(), machine.resolution())?;
+        }
+        if let Some(audio) = audio.as_mut() {
+            audio.add_frame(machine, false)?;
+        }
+        if result.is_err() || machine.has_exited() {
+            break;
+        }
+    }
+    if let Some(video) = video {
+        video.finish()?;
+    }
+    if let Some(audio) = audio {
+        audio.finish()?;
+    }
+    let (buffer, resolution) = (machine.framebuffer(), machine.resolution());
+    let dump = match format {
+        DumpFormat::Ascii => snapshot::ascii(buffer, resolution),
+        DumpFormat::Hash => format!("{:016x}\n", snapshot::hash(buffer, resolution)),
+        DumpFormat::Pbm => snapshot::pbm(buffer, resolution),
+    };
+    match &output {
+        Some(path) => std::fs::write(path, dump)
+            .map_err(|e| format!("failed to write {}: {}", path.display(), e))?,
+        None => print!("{}", dump),
+    }
+    result.map_err(|e| format!("CPU error: {}", e))
+}
+
+/// Pass a key to the machine, recording it if a movie is being recorded.
+/// Keys are ignored while a movie is replaying.
+fn press_key(
+    machine: &mut Machine,
+    recorder: Option<&mut Recorder>,
+    player: &Option<Player>,
+    key: u8,
+    pressed: bool,
+) {
+    match recorder {
+        Some(recorder) => recorder.press_key(machine, key, pressed),
+        None if player.is_none() => machine.press_key(key, pressed),
+        None => {}
+    }
 }
@@ -1,41 +1,97 @@
-const RAM_SIZE: usize = 4096;
+use crate::state::{Reader, StateError, Writer};
 
-#[derive(Debug)]
-pub struct Memory([u8; RAM_SIZE]);
+const RAM_SIZE: usize = 0x1000;
+/// XO-CHIP programs can address the full 64 KiB.
+const EXTENDED_RAM_SIZE: usize = 0x10000;
+
+/// An attempt to access an address outside of RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfBounds(pub u16);
+
+/// The address `n` bytes past `pos`, an address past 0xFFFF is reported as
+/// 0xFFFF, the last address, which the access ran past.
+pub fn offset(pos: u16, n: u16) -> Result<u16, OutOfBounds> {
+    pos.checked_add(n).ok_or(OutOfBounds(u16::MAX))
+}
+
+#[derive(Clone, Debug)]
+pub struct Memory(Vec<u8>);
 
 impl Memory {
+    /// The 4 KiB of the original interpreters.
     pub fn new() -> Memory {
-        Memory([0; RAM_SIZE])
+        Memory(vec![0; RAM_SIZE])
+    }
+
+    /// The 64 KiB of XO-CHIP.
+    pub fn extended() -> Memory {
+        Memory(vec![0; EXTENDED_RAM_SIZE])
     }
 
     pub fn size(&self) -> usize {
         self.0.len()
     }
 
-    pub fn load(&mut self, src: &[u8], pos: u16) {
+    fn index(&self, pos: u16) -> Result<usize, OutOfBounds> {
+        let idx = pos as usize;
+        if idx < self.0.len() {
+            Ok(idx)
+        } else {
+            Err(OutOfBounds(pos))
+        }
+    }
+
+    pub fn load(&mut self, src: &[u8], pos: u16) -> Result<(), OutOfBounds> {
         let range = (pos as usize)..pos as usize + src.len();
+        if range.end > self.0.len() {
+            // report the last address, which the load ran past
+            return Err(OutOfBounds((self.0.len() - 1) as u16));
+        }
         self.0[range].copy_from_slice(src);
+        Ok(())
     }
 
-    pub fn read_u16(&self, pos: u16) -> u16 {
-        let b1 = self.0[pos as usize] as u16;
-        let b2 = self.0[(pos + 1) as usize] as u16;
-        (b1 << 8) | b2
+    pub fn read_u16(&self, pos: u16) -> Result<u16, OutOfBounds> {
+        let b1 = self.read_u8(pos)? as u16;
+        let b2 = self.read_u8(offset(pos, 1)?)? as u16;
+        Ok((b1 << 8) | b2)
     }
 
-    pub fn read_u8(&self, pos: u16) -> u8 {
-        self.0[pos as usize]
+    pub fn read_u8(&self, pos: u16) -> Result<u8, OutOfBounds> {
+        Ok(self.0[self.index(pos)?])
     }
 
-    pub fn write_u16(&mut self, pos: u16, val: u16) {
+    pub fn write_u16(&mut self, pos: u16, val: u16) -> Result<(), OutOfBounds> {
         let b1 = (val >> 8) as u8;
         let b2 = val as u8;
-        self.0[pos as usize] = b1;
-        self.0[(pos + 1) as usize] = b2;
+        // check both bytes before writing so a failed write leaves memory untouched
+        let i1 = self.index(pos)?;
+        let i2 = self.index(offset(pos, 1)?)?;
+        self.0[i1] = b1;
+        self.0[i2] = b2;
+        Ok(())
+    }
+
+    pub fn write_u8(&mut self, pos: u16, val: u8) -> Result<(), OutOfBounds> {
+        let idx = self.index(pos)?;
+        self.0[idx] = val;
+        Ok(())
+    }
+
+    pub(crate) fn write_state(&self, w: &mut Writer) {
+        w.bytes(&self.0);
+    }
+
+    pub(crate) fn read_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        let len = self.0.len();
+        self.0.copy_from_slice(r.bytes(len)?);
+        Ok(())
     }
+}
 
-    pub fn write_u8(&mut self, pos: u16, val: u8) {
-        self.0[pos as usize] = val;
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -45,35 +101,65 @@ mod tests {
 
     #[test]
     fn test_size() {
-        let mem = Memory::new();
-        assert_eq!(mem.size(), RAM_SIZE);
+        assert_eq!(Memory::new().size(), RAM_SIZE);
+        assert_eq!(Memory::extended().size(), EXTENDED_RAM_SIZE);
     }
 
     #[test]
     fn test_load() {
         let bytes = [0xFF; 64];
         let mut mem = Memory::new();
-        mem.load(&bytes, 1024);
-        assert_eq!(mem.read_u8(1024), 0xFF);
-        assert_eq!(mem.read_u8(1024 + 63), 0xFF);
-        assert_eq!(mem.read_u8(1024 + 64), 0x00);
+        mem.load(&bytes, 1024).unwrap();
+        assert_eq!(mem.read_u8(1024), Ok(0xFF));
+        assert_eq!(mem.read_u8(1024 + 63), Ok(0xFF));
+        assert_eq!(mem.read_u8(1024 + 64), Ok(0x00));
+    }
+
+    #[test]
+    fn test_load_out_of_bounds() {
+        let bytes = [0xFF; 64];
+        let mut mem = Memory::new();
+        assert_eq!(mem.load(&bytes, 4064), Err(OutOfBounds(4095)));
+        // nothing should have been written
+        assert_eq!(mem.read_u8(4064), Ok(0x00));
+
+        let mut mem = Memory::extended();
+        mem.load(&bytes, 4064).unwrap();
+        assert_eq!(mem.load(&bytes, 0xFFE0), Err(OutOfBounds(0xFFFF)));
     }
 
     #[test]
     fn test_read_write_u8() {
         let mut mem = Memory::new();
-        mem.write_u8(0, 0xFF);
-        assert_eq!(mem.read_u8(0), 0xFF);
+        mem.write_u8(0, 0xFF).unwrap();
+        assert_eq!(mem.read_u8(0), Ok(0xFF));
         // next byte shouldn't be affected
-        assert_eq!(mem.read_u8(1), 0x00);
+        assert_eq!(mem.read_u8(1), Ok(0x00));
     }
 
     #[test]
     fn test_read_write_u16() {
         let mut mem = Memory::new();
-        mem.write_u16(0, 0xFFFF);
-        assert_eq!(mem.read_u16(0), 0xFFFF);
-        assert_eq!(mem.read_u16(1), 0xFF00);
-        assert_eq!(mem.read_u16(2), 0x0000);
+        mem.write_u16(0, 0xFFFF).unwrap();
+        assert_eq!(mem.read_u16(0), Ok(0xFFFF));
+        assert_eq!(mem.read_u16(1), Ok(0xFF00));
+        assert_eq!(mem.read_u16(2), Ok(0x0000));
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let mut mem = Memory::new();
+        assert_eq!(mem.read_u8(4096), Err(OutOfBounds(4096)));
+        assert_eq!(mem.read_u16(4095), Err(OutOfBounds(4096)));
+        assert_eq!(mem.write_u8(4096, 0xFF), Err(OutOfBounds(4096)));
+        assert_eq!(mem.write_u16(4095, 0xFFFF), Err(OutOfBounds(4096)));
+        // a failed u16 write shouldn't write the first byte
+        assert_eq!(mem.read_u8(4095), Ok(0x00));
+
+        let mut mem = Memory::extended();
+        assert_eq!(mem.read_u8(0xFFFF), Ok(0x00));
+        assert_eq!(mem.read_u16(0xFFFF), Err(OutOfBounds(0xFFFF)));
+        assert_eq!(mem.write_u16(0xFFFF, 0xFFFF), Err(OutOfBounds(0xFFFF)));
+        assert_eq!(mem.read_u8(0xFFFF), Ok(0x00));
     }
 }
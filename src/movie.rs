@@ -0,0 +1,335 @@
+use crate::cpu::CpuError;
+use crate::machine::Machine;
+use crate::quirks::Quirks;
+use crate::state::{Reader, StateError, Writer};
+
+/// identifies a movie file
+const MAGIC: &[u8; 4] = b"C8MV";
+/// bumped whenever the layout of a movie changes
+pub const VERSION: u16 = 3;
+
+/// A key being pressed or released before the given frame is run. Inputs
+/// given after the last frame has run have a frame of `Movie::frames`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Input {
+    pub frame: u32,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// A recording of the keypad input of a run from power on, along with the
+/// random seed, speed and quirks, which is all that's needed to reproduce the
+/// run exactly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    rom_hash: u64,
+    seed: u64,
+    /// see `Machine::cycles_per_frame`
+    cycles_per_frame: u32,
+    /// the interpreter behaviour the run was recorded with
+    quirks: Quirks,
+    /// number of frames recorded
+    frames: u32,
+    /// ordered by frame
+    inputs: Vec<Input>,
+}
+
+impl Movie {
+    /// The seed to create the machine with when replaying.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The instructions per frame to set on the machine when replaying.
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    /// The quirks to create the machine with when replaying.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn inputs(&self) -> &[Input] {
+        &self.inputs
+    }
+
+    /// Serialize the movie, see `load`.
+    pub fn save(&self) -> Vec<u8> {
+        let mut w = Writer::with_header(MAGIC, VERSION, self.rom_hash);
+        w.u64(self.seed);
+        w.u32(self.cycles_per_frame);
+        self.quirks.write_state(&mut w);
+        w.u32(self.frames);
+        for input in &self.inputs {
+            w.u32(input.frame);
+            w.u8(input.key);
+            w.bool(input.pressed);
+        }
+        w.finish()
+    }
+
+    /// Read back a movie from `save`, it has to have been recorded with the
+    /// ROM that `rom_hash` was computed from.
+    pub fn load(data: &[u8], rom_hash: u64) -> Result<Self, StateError> {
+        let mut r = Reader::with_header(data, MAGIC, VERSION, rom_hash)?;
+        let seed = r.u64()?;
+        let cycles_per_frame = r.u32()?;
+        if cycles_per_frame == 0 {
+            return Err(StateError::InvalidFormat);
+        }
+        let quirks = Quirks::read_state(&mut r)?;
+        let frames = r.u32()?;
+        let mut inputs: Vec<Input> = vec![];
+        while !r.is_empty() {
+            let input = Input {
+                frame: r.u32()?,
+                key: r.u8()?,
+                pressed: r.bool()?,
+            };
+            let in_order = inputs.last().is_none_or(|last| last.frame <= input.frame);
+            if input.key >= 0x10 || input.frame > frames || !in_order {
+                return Err(StateError::InvalidFormat);
+            }
+            inputs.push(input);
+        }
+        Ok(Self {
+            rom_hash,
+            seed,
+            cycles_per_frame,
+            quirks,
+            frames,
+            inputs,
+        })
+    }
+}
+
+/// Records the input given to a machine as it runs.
+#[derive(Debug)]
+pub struct Recorder {
+    movie: Movie,
+}
+
+impl Recorder {
+    /// Start recording `machine`, which should have just been created with
+    /// `Machine::with_seed`. Returns `None` for machines without a seed as
+    /// their runs can't be reproduced.
+    pub fn new(machine: &Machine) -> Option<Self> {
+        Some(Self {
+            movie: Movie {
+                rom_hash: machine.rom_hash(),
+                seed: machine.seed()?,
+                cycles_per_frame: machine.cycles_per_frame(),
+                quirks: machine.cpu().quirks(),
+                frames: 0,
+                inputs: vec![],
+            },
+        })
+    }
+
+    /// Press or release a key on `machine`, recording it if it changed.
+    pub fn press_key(&mut self, machine: &mut Machine, key: u8, pressed: bool) {
+        if machine.cpu().keys()[key as usize] == pressed {
+            return;
+        }
+        machine.press_key(key, pressed);
+        self.movie.inputs.push(Input {
+            frame: self.movie.frames,
+            key,
+            pressed,
+        });
+    }
+
+    /// Run a frame on `machine`, counting it towards the recording.
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Result<(), CpuError> {
+        self.movie.frames += 1;
+        machine.run_frame()
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
+
+/// Feeds the input of a movie back into a machine frame by frame.
+#[derive(Debug)]
+pub struct Player {
+    movie: Movie,
+    frame: u32,
+    /// index of the next input to apply
+    next: usize,
+}
+
+impl Player {
+    /// Replay `movie`, the machine it runs on should have been created with
+    /// the movie's seed and quirks and have its cycles per frame set to the
+    /// movie's.
+    pub fn new(movie: Movie) -> Self {
+        Self {
+            movie,
+            frame: 0,
+            next: 0,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Whether every recorded frame has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames
+    }
+
+    /// Apply the input recorded for the next frame and run it.
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Result<(), CpuError> {
+        self.apply_inputs(machine);
+        self.frame += 1;
+        let result = machine.run_frame();
+        // keys pressed after the last frame, so the machine ends up with the
+        // keys it was recorded with
+        if self.is_finished() {
+            self.apply_inputs(machine);
+        }
+        result
+    }
+
+    /// Press and release the keys recorded up to the current frame.
+    fn apply_inputs(&mut self, machine: &mut Machine) {
+        let inputs = &self.movie.inputs[self.next..];
+        let count = inputs.partition_point(|input| input.frame <= self.frame);
+        for input in &inputs[..count] {
+            machine.press_key(input.key, input.pressed);
+        }
+        self.next += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state;
+
+    // 6205: LD V2, 0x05
+    // C03F: RND V0, 0x3F
+    // C11F: RND V1, 0x1F
+    // F029: LD F, V0
+    // E2A1: SKNP V2
+    // D015: DRW V0, V1, 5
+    // 1202: JP 0x202
+    const ROM: [u8; 14] = [
+        0x62, 0x05, 0xC0, 0x3F, 0xC1, 0x1F, 0xF0, 0x29, 0xE2, 0xA1, 0xD0, 0x15, 0x12, 0x02,
+    ];
+
+    fn record() -> (Movie, Machine) {
+        record_with(Quirks::default(), |_, _| {})
+    }
+
+    /// Record 30 frames with `quirks`, then call `after` with the recorder.
+    fn record_with<F>(quirks: Quirks, after: F) -> (Movie, Machine)
+    where
+        F: FnOnce(&mut Recorder, &mut Machine),
+    {
+        let mut machine = Machine::with_seed(&ROM, quirks, 7).unwrap();
+        machine.set_cycles_per_frame(12);
+        let mut recorder = Recorder::new(&machine).unwrap();
+        for frame in 0..30 {
+            match frame {
+                3 | 20 => recorder.press_key(&mut machine, 5, true),
+                // repeated presses aren't recorded
+                4 => recorder.press_key(&mut machine, 5, true),
+                10 | 25 => recorder.press_key(&mut machine, 5, false),
+                _ => {}
+            }
+            recorder.run_frame(&mut machine).unwrap();
+        }
+        after(&mut recorder, &mut machine);
+        (recorder.movie().clone(), machine)
+    }
+
+    #[test]
+    fn test_unseeded() {
+        let machine = Machine::new(&ROM, Quirks::default()).unwrap();
+        assert!(Recorder::new(&machine).is_none());
+    }
+
+    fn replay(movie: &Movie) -> Machine {
+        let mut machine = Machine::with_seed(&ROM, movie.quirks(), movie.seed()).unwrap();
+        machine.set_cycles_per_frame(movie.cycles_per_frame());
+        let mut player = Player::new(movie.clone());
+        while !player.is_finished() {
+            player.run_frame(&mut machine).unwrap();
+        }
+        machine
+    }
+
+    #[test]
+    fn test_record() {
+        let (movie, _) = record();
+        assert_eq!(movie.frames(), 30);
+        assert_eq!(movie.cycles_per_frame(), 12);
+        assert_eq!(movie.inputs().len(), 4);
+        assert_eq!(
+            movie.inputs()[1],
+            Input {
+                frame: 10,
+                key: 5,
+                pressed: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let (movie, recorded) = record();
+        assert!(recorded.framebuffer().iter().any(|p| *p != 0));
+        for _ in 0..2 {
+            let replayed = replay(&movie);
+            assert_eq!(replayed.framebuffer(), recorded.framebuffer());
+            assert_eq!(replayed.save_state(), recorded.save_state());
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let (movie, _) = record();
+        let data = movie.save();
+        let hash = state::rom_hash(&ROM);
+        assert_eq!(Movie::load(&data, hash), Ok(movie));
+        assert_eq!(Movie::load(&data, hash + 1), Err(StateError::RomMismatch));
+        assert_eq!(
+            Movie::load(&data[..data.len() - 1], hash),
+            Err(StateError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn test_input_after_last_frame() {
+        // e.g. a key pressed in the same batch of events as quitting
+        let (movie, recorded) = record_with(Quirks::default(), |recorder, machine| {
+            recorder.press_key(machine, 0xA, true);
+        });
+        assert_eq!(movie.inputs().last().unwrap().frame, movie.frames());
+        let hash = state::rom_hash(&ROM);
+        let loaded = Movie::load(&movie.save(), hash).unwrap();
+        assert_eq!(loaded, movie);
+        assert_eq!(replay(&loaded).save_state(), recorded.save_state());
+    }
+
+    #[test]
+    fn test_quirks() {
+        let (movie, recorded) = record_with(Quirks::SUPER_CHIP, |_, _| {});
+        let hash = state::rom_hash(&ROM);
+        let loaded = Movie::load(&movie.save(), hash).unwrap();
+        assert_eq!(loaded.quirks(), Quirks::SUPER_CHIP);
+        assert_eq!(replay(&loaded).framebuffer(), recorded.framebuffer());
+
+        // the same input under another preset plays out differently
+        let (_, other) = record_with(Quirks::COSMAC_VIP, |_, _| {});
+        assert_ne!(other.framebuffer(), recorded.framebuffer());
+    }
+}
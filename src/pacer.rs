@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Time a frame takes out of the accumulator, a second at full speed.
+const FRAME_COST: u128 = NANOS_PER_SEC * 100;
+
+/// Keeps emulated frames in step with real time, independent of how often the
+/// caller manages to update or draw.
+///
+/// Elapsed time goes into an accumulator that frames are taken out of, so
+/// time spent anywhere in the caller counts and short and long updates even
+/// out. Time is kept in nanoseconds multiplied by the frame rate and the speed
+/// percentage, which makes a frame a whole number of units and stops the rate
+/// drifting.
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    fps: u128,
+    /// percentage of the normal frame rate to run at
+    speed: u32,
+    /// most frames `advance` returns at once at normal speed
+    max_frames: u32,
+    /// time not yet spent on frames, in nanoseconds times `fps` times `speed`
+    accumulator: u128,
+}
+
+impl Pacer {
+    pub fn new(fps: u32, max_frames: u32) -> Self {
+        Self {
+            fps: fps as u128,
+            speed: 100,
+            max_frames,
+            accumulator: 0,
+        }
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    /// Run at `percent` of the normal frame rate, at least 1. Time already
+    /// accumulated is kept as the same fraction of a frame.
+    pub fn set_speed(&mut self, percent: u32) {
+        self.speed = percent.max(1);
+    }
+
+    /// Account for `elapsed` real time and return the number of frames now
+    /// due. At most `max_frames` are returned, scaled up when running fast,
+    /// and time beyond that is dropped so a machine that can't keep up runs
+    /// the emulation slower rather than falling further and further behind.
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.accumulator += elapsed.as_nanos() * self.fps * self.speed as u128;
+        let due = self.accumulator / FRAME_COST;
+        let max_frames = self.max_frames * self.speed.div_ceil(100);
+        if due > max_frames as u128 {
+            self.accumulator = 0;
+            return max_frames;
+        }
+        self.accumulator -= due * FRAME_COST;
+        due as u32
+    }
+
+    /// Time left until the next frame is due.
+    pub fn until_next_frame(&self) -> Duration {
+        let nanos = (FRAME_COST - self.accumulator).div_ceil(self.fps * self.speed as u128);
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_nanos(16_666_667);
+
+    #[test]
+    fn test_advance() {
+        let mut pacer = Pacer::new(60, 4);
+        assert_eq!(pacer.advance(Duration::ZERO), 0);
+        assert_eq!(pacer.advance(FRAME / 2), 0);
+        assert_eq!(pacer.advance(FRAME - FRAME / 2), 1);
+        assert_eq!(pacer.advance(FRAME * 2), 2);
+    }
+
+    #[test]
+    fn test_no_drift() {
+        // a minute of updates at an awkward rate gives exactly 3600 frames
+        let mut pacer = Pacer::new(60, 4);
+        let frames: u32 = (0..8000)
+            .map(|_| pacer.advance(Duration::from_secs(60) / 8000))
+            .sum();
+        assert_eq!(frames, 3600);
+    }
+
+    #[test]
+    fn test_catch_up_limit() {
+        let mut pacer = Pacer::new(60, 4);
+        assert_eq!(pacer.advance(Duration::from_secs(1)), 4);
+        // the rest of the second is dropped
+        assert_eq!(pacer.advance(Duration::ZERO), 0);
+        assert_eq!(pacer.until_next_frame(), FRAME);
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut pacer = Pacer::new(60, 8);
+        pacer.set_speed(200);
+        assert_eq!(pacer.advance(FRAME * 2), 4);
+        pacer.set_speed(50);
+        assert_eq!(pacer.advance(FRAME), 0);
+        assert_eq!(pacer.advance(FRAME), 1);
+        let left = pacer.until_next_frame();
+        assert!(left > FRAME * 2 - Duration::from_nanos(10) && left <= FRAME * 2);
+        pacer.set_speed(200);
+        assert_eq!(pacer.advance(Duration::from_secs(1)), 16);
+        pacer.set_speed(0);
+        assert_eq!(pacer.speed(), 1);
+    }
+
+    #[test]
+    fn test_until_next_frame() {
+        let mut pacer = Pacer::new(60, 4);
+        pacer.advance(FRAME / 4);
+        let left = pacer.until_next_frame();
+        assert!(left > FRAME * 3 / 4 - Duration::from_nanos(2) && left < FRAME);
+    }
+}
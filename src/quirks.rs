@@ -0,0 +1,113 @@
+use crate::state::{Reader, StateError, Writer};
+
+/// Behaviours of instructions that differ between Chip8 interpreters.
+///
+/// The `Default` matches what this emulator has always done, the associated
+/// constants are presets for the more common interpreters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VX in place instead of shifting VY into VX
+    pub shift: bool,
+    /// FX55/FX65 leave I pointing past the last register stored/loaded
+    pub load_store_increment: bool,
+    /// BNNN behaves as BXNN, jumping to XNN + VX instead of NNN + V0
+    pub jump: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0
+    pub vf_reset: bool,
+    /// FX1E sets VF to 1 when I overflows past 0xFFF and to 0 when it
+    /// doesn't, unless 64 KiB of memory can be addressed
+    pub index_overflow: bool,
+    /// sprites are clipped at the edges of the screen instead of wrapping
+    pub clipping: bool,
+    /// DXYN waits for the next frame, allowing at most one draw per frame
+    pub display_wait: bool,
+    /// 64 KiB of memory can be addressed instead of 4 KiB
+    pub extended_memory: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift: false,
+        load_store_increment: true,
+        jump: false,
+        vf_reset: true,
+        index_overflow: false,
+        clipping: true,
+        display_wait: true,
+        extended_memory: false,
+    };
+
+    pub const CHIP48: Quirks = Quirks {
+        shift: true,
+        load_store_increment: true,
+        jump: true,
+        vf_reset: false,
+        index_overflow: false,
+        clipping: true,
+        display_wait: false,
+        extended_memory: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift: true,
+        load_store_increment: false,
+        jump: true,
+        vf_reset: false,
+        index_overflow: false,
+        clipping: true,
+        display_wait: false,
+        extended_memory: false,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift: false,
+        load_store_increment: true,
+        jump: false,
+        vf_reset: false,
+        index_overflow: false,
+        clipping: false,
+        display_wait: false,
+        extended_memory: true,
+    };
+}
+
+impl Quirks {
+    pub(crate) fn write_state(&self, w: &mut Writer) {
+        w.bool(self.shift);
+        w.bool(self.load_store_increment);
+        w.bool(self.jump);
+        w.bool(self.vf_reset);
+        w.bool(self.index_overflow);
+        w.bool(self.clipping);
+        w.bool(self.display_wait);
+        w.bool(self.extended_memory);
+    }
+
+    pub(crate) fn read_state(r: &mut Reader) -> Result<Self, StateError> {
+        Ok(Self {
+            shift: r.bool()?,
+            load_store_increment: r.bool()?,
+            jump: r.bool()?,
+            vf_reset: r.bool()?,
+            index_overflow: r.bool()?,
+            clipping: r.bool()?,
+            display_wait: r.bool()?,
+            extended_memory: r.bool()?,
+        })
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift: false,
+            load_store_increment: false,
+            jump: false,
+            vf_reset: true,
+            index_overflow: true,
+            clipping: false,
+            display_wait: false,
+            extended_memory: false,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+/// A bounded history of machine states for stepping backwards frame by frame.
+///
+/// Only the most recent state is kept in full, older states are stored as
+/// the difference from the state after them. Consecutive frames usually only
+/// differ by a handful of bytes, so the deltas are XORed and run length
+/// encoded to keep memory usage small.
+#[derive(Debug)]
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    /// the oldest delta is at the front
+    deltas: VecDeque<Vec<u8>>,
+}
+
+/// Append `n` as a variable length integer, 7 bits at a time.
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    while let Some((&b, rest)) = data.split_first() {
+        *data = rest;
+        n |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    n
+}
+
+/// Encode `a XOR b` as runs of (unchanged length, changed length, changed
+/// bytes), both slices must be the same length.
+fn encode_delta(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < a.len() {
+        let same = a[i..]
+            .iter()
+            .zip(&b[i..])
+            .take_while(|(x, y)| x == y)
+            .count();
+        i += same;
+        let changed = a[i..]
+            .iter()
+            .zip(&b[i..])
+            .take_while(|(x, y)| x != y)
+            .count();
+        write_varint(&mut out, same);
+        write_varint(&mut out, changed);
+        out.extend(a[i..i + changed].iter().zip(&b[i..]).map(|(x, y)| x ^ y));
+        i += changed;
+    }
+    out
+}
+
+/// Apply a delta from `encode_delta` to `state` in place.
+fn apply_delta(state: &mut [u8], mut delta: &[u8]) {
+    let mut i = 0;
+    while !delta.is_empty() {
+        i += read_varint(&mut delta);
+        let changed = read_varint(&mut delta);
+        let (bytes, rest) = delta.split_at(changed);
+        for (s, d) in state[i..i + changed].iter_mut().zip(bytes) {
+            *s ^= d;
+        }
+        delta = rest;
+        i += changed;
+    }
+}
+
+impl Rewind {
+    /// Keep up to `capacity` states, including the most recent one.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Number of states that can be rewound to.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Approximate number of bytes used by the stored states.
+    pub fn memory_usage(&self) -> usize {
+        let latest = self.latest.as_ref().map_or(0, |s| s.len());
+        latest + self.deltas.iter().map(|d| d.len()).sum::<usize>()
+    }
+
+    /// Record the state of a new frame, dropping the oldest state when full.
+    /// States are expected to come from `Machine::save_state` for the same ROM.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        match self.latest.take() {
+            Some(prev) if prev.len() == state.len() => {
+                self.deltas.push_back(encode_delta(&state, &prev));
+                if self.deltas.len() >= self.capacity {
+                    self.deltas.pop_front();
+                }
+            }
+            // can't diff states of different sizes, start the history over
+            _ => self.deltas.clear(),
+        }
+        self.latest = Some(state);
+    }
+
+    /// Step back to the previous frame, returning its state, or `None` if
+    /// there's no more history.
+    pub fn pop(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+        apply_delta(latest, &delta);
+        Some(latest)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let a = vec![0u8; 300];
+        let mut b = a.clone();
+        b[0] = 1;
+        b[150] = 2;
+        b[151] = 3;
+        b[299] = 4;
+        let delta = encode_delta(&a, &b);
+        assert!(delta.len() < 16);
+        let mut state = b.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, a);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut rewind = Rewind::new(3);
+        for i in 0..5u8 {
+            rewind.push(vec![i; 8]);
+        }
+        assert_eq!(rewind.len(), 2);
+        assert_eq!(rewind.pop(), Some(&[3; 8][..]));
+        assert_eq!(rewind.pop(), Some(&[2; 8][..]));
+        assert_eq!(rewind.pop(), None);
+
+        // carries on from the rewound state
+        rewind.push(vec![9; 8]);
+        assert_eq!(rewind.pop(), Some(&[2; 8][..]));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut rewind = Rewind::new(0);
+        rewind.push(vec![1; 8]);
+        rewind.push(vec![2; 8]);
+        assert_eq!(rewind.pop(), None);
+        assert_eq!(rewind.memory_usage(), 0);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::state::{Reader, StateError, Writer};
+use rand::Rng as _;
+
+/// Source of the random numbers used by CXNN.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rng {
+    /// SplitMix64 generator, the value is its current state
+    Seeded(u64),
+    /// the thread local generator from `rand`, runs can't be reproduced
+    Thread,
+    /// returns `values` in order, starting over once they run out
+    Scripted { values: Vec<u8>, next: usize },
+}
+
+impl Rng {
+    pub fn seeded(seed: u64) -> Self {
+        Rng::Seeded(seed)
+    }
+
+    pub fn scripted(values: Vec<u8>) -> Self {
+        Rng::Scripted { values, next: 0 }
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        match self {
+            Rng::Seeded(state) => {
+                *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                (z ^ (z >> 31)) as u8
+            }
+            Rng::Thread => rand::thread_rng().gen(),
+            Rng::Scripted { values, next } => {
+                let val = values.get(*next).copied().unwrap_or(0);
+                *next = (*next + 1) % values.len().max(1);
+                val
+            }
+        }
+    }
+
+    pub(crate) fn write_state(&self, w: &mut Writer) {
+        match self {
+            Rng::Seeded(state) => {
+                w.u8(0);
+                w.u64(*state);
+            }
+            Rng::Thread => w.u8(1),
+            Rng::Scripted { values, next } => {
+                w.u8(2);
+                w.u32(values.len() as u32);
+                w.bytes(values);
+                w.u32(*next as u32);
+            }
+        }
+    }
+
+    pub(crate) fn read_state(r: &mut Reader) -> Result<Self, StateError> {
+        match r.u8()? {
+            0 => Ok(Rng::Seeded(r.u64()?)),
+            1 => Ok(Rng::Thread),
+            2 => {
+                let len = r.u32()? as usize;
+                let values = r.bytes(len)?.to_vec();
+                let next = r.u32()? as usize;
+                if next >= len.max(1) {
+                    return Err(StateError::InvalidFormat);
+                }
+                Ok(Rng::Scripted { values, next })
+            }
+            _ => Err(StateError::InvalidFormat),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded() {
+        let mut a = Rng::seeded(42);
+        let mut b = Rng::seeded(42);
+        let seq: Vec<u8> = (0..16).map(|_| a.next_u8()).collect();
+        assert_eq!(seq, (0..16).map(|_| b.next_u8()).collect::<Vec<_>>());
+        // not stuck on a single value
+        assert!(seq.iter().any(|v| *v != seq[0]));
+    }
+
+    #[test]
+    fn test_scripted() {
+        let mut rng = Rng::scripted(vec![1, 2, 3]);
+        let seq: Vec<u8> = (0..5).map(|_| rng.next_u8()).collect();
+        assert_eq!(seq, vec![1, 2, 3, 1, 2]);
+        assert_eq!(Rng::scripted(vec![]).next_u8(), 0);
+    }
+
+    #[test]
+    fn test_state() {
+        for mut rng in [Rng::seeded(7), Rng::Thread, Rng::scripted(vec![4, 5])] {
+            rng.next_u8();
+            let mut w = Writer::new(0);
+            rng.write_state(&mut w);
+            let data = w.finish();
+            let mut r = Reader::new(&data, 0).unwrap();
+            assert_eq!(Rng::read_state(&mut r), Ok(rng));
+            assert_eq!(r.finish(), Ok(()));
+        }
+    }
+}
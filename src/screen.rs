@@ -1,41 +1,316 @@
-use crate::memory::Memory;
+use crate::memory::{self, Memory, OutOfBounds};
+use crate::state::{Reader, StateError, Writer};
 
 pub const WIDTH: usize = 64;
 pub const HEIGHT: usize = 32;
-// screen always has 64x32, it can be upscaled by the renderer
+// screen always has 64x32 in lores, it can be upscaled by the renderer
 pub const SIZE: usize = WIDTH * HEIGHT;
 
-#[derive(Debug)]
-pub struct Screen([bool; SIZE]);
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+pub const HIRES_SIZE: usize = HIRES_WIDTH * HIRES_HEIGHT;
+
+/// Number of XO-CHIP bitplanes.
+pub const NUM_PLANES: u8 = 2;
+const ALL_PLANES: u8 = (1 << NUM_PLANES) - 1;
+
+/// Each pixel is a bitmask of the planes it is set in, so plain Chip8 and
+/// SUPER-CHIP programs only ever produce 0 or 1.
+#[derive(Clone, Debug)]
+pub struct Screen {
+    pixels: [u8; HIRES_SIZE],
+    hires: bool,
+    /// bitmask of the planes affected by drawing, clearing and scrolling
+    planes: u8,
+}
 
 impl Screen {
     pub fn new() -> Self {
-        Self([false; SIZE])
+        Self {
+            pixels: [0; HIRES_SIZE],
+            hires: false,
+            planes: 0b01,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            HEIGHT
+        }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switch between 64x32 and 128x64, all planes are cleared when switching.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixels.fill(0);
+    }
+
+    /// Select which planes are affected by drawing, clearing and scrolling.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & ALL_PLANES;
     }
 
-    pub fn buffer(&self) -> &[bool; SIZE] {
-        &self.0
+    /// The pixels of the current resolution, stored row by row.
+    pub fn buffer(&self) -> &[u8] {
+        &self.pixels[..self.width() * self.height()]
     }
 
+    /// Clear the selected planes.
     pub fn clear(&mut self) {
-        self.0.fill(false);
+        let planes = self.planes;
+        self.pixels.iter_mut().for_each(|p| *p &= !planes);
+    }
+
+    /// XOR a sprite onto the selected planes, returns whether any pixels were
+    /// erased.
+    ///
+    /// Sprites are 8 pixels wide and `rows` tall, a `rows` of 0 draws a
+    /// 16x16 sprite instead. When multiple planes are selected the sprite
+    /// data for each plane follows the previous one in memory. The starting
+    /// position always wraps around the screen, with `clip` set the parts of
+    /// the sprite that go past the edges are discarded instead of wrapping.
+    pub fn draw(
+        &mut self,
+        memory: &Memory,
+        i: u16,
+        x: u8,
+        y: u8,
+        rows: u8,
+        clip: bool,
+    ) -> Result<bool, OutOfBounds> {
+        let (sprite_width, rows) = if rows == 0 {
+            (16, 16)
+        } else {
+            (8, rows as u16)
+        };
+        let sprite_size = rows * sprite_width / 8;
+        let mut start = 0;
+        let mut f_flag = false;
+        for plane in 0..NUM_PLANES {
+            let plane = 1 << plane;
+            if self.planes & plane != 0 {
+                let i = memory::offset(i, start)?;
+                f_flag |= self.draw_plane(memory, i, x, y, sprite_width, rows, clip, plane)?;
+                start += sprite_size;
+            }
+        }
+        Ok(f_flag)
     }
 
-    pub fn draw(&mut self, memory: &Memory, i: u16, x: u8, y: u8, rows: u8) -> bool {
+    #[allow(clippy::too_many_arguments)]
+    fn draw_plane(
+        &mut self,
+        memory: &Memory,
+        i: u16,
+        x: u8,
+        y: u8,
+        sprite_width: u16,
+        rows: u16,
+        clip: bool,
+        plane: u8,
+    ) -> Result<bool, OutOfBounds> {
+        let (width, height) = (self.width(), self.height());
+        let bytes_per_row = sprite_width / 8;
+        let x = x as usize % width;
+        let y = y as usize % height;
         let mut f_flag = false;
-        for y_line in 0..(rows as u16) {
-            let pixels = memory.read_u8(i + y_line);
-            for x_line in 0..8 {
-                if (pixels & (0b1000_0000 >> x_line)) != 0 {
-                    let x = (x as u16 + x_line) as usize % WIDTH;
-                    let y = (y as u16 + y_line) as usize % HEIGHT;
-                    let idx = x + WIDTH * y;
-
-                    f_flag |= self.0[idx];
-                    self.0[idx] ^= true;
+        for y_line in 0..rows {
+            let addr = memory::offset(i, y_line * bytes_per_row)?;
+            let pixels = if sprite_width == 16 {
+                memory.read_u16(addr)?
+            } else {
+                (memory.read_u8(addr)? as u16) << 8
+            };
+            let y = y + y_line as usize;
+            if clip && y >= height {
+                break;
+            }
+            for x_line in 0..sprite_width as usize {
+                let x = x + x_line;
+                if clip && x >= width {
+                    break;
+                }
+                if (pixels & (0b1000_0000_0000_0000 >> x_line)) != 0 {
+                    let idx = (x % width) + width * (y % height);
+
+                    f_flag |= self.pixels[idx] & plane != 0;
+                    self.pixels[idx] ^= plane;
                 }
             }
         }
-        f_flag
+        Ok(f_flag)
+    }
+
+    /// Move the selected planes by (`dx`, `dy`), pixels moved off the edges
+    /// are lost and the uncovered area is cleared.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width(), self.height());
+        let planes = self.planes;
+        let src = self.pixels;
+        for y in 0..height {
+            for x in 0..width {
+                let src_x = x as isize - dx;
+                let src_y = y as isize - dy;
+                let moved = if (0..width as isize).contains(&src_x)
+                    && (0..height as isize).contains(&src_y)
+                {
+                    src[src_x as usize + width * src_y as usize]
+                } else {
+                    0
+                };
+                let idx = x + width * y;
+                self.pixels[idx] = (self.pixels[idx] & !planes) | (moved & planes);
+            }
+        }
+    }
+
+    /// Scroll the screen down by `n` pixels.
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    /// Scroll the screen up by `n` pixels.
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    /// Scroll the screen right by `n` pixels.
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    /// Scroll the screen left by `n` pixels.
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    pub(crate) fn write_state(&self, w: &mut Writer) {
+        w.bool(self.hires);
+        w.u8(self.planes);
+        w.bytes(&self.pixels);
+    }
+
+    pub(crate) fn read_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.hires = r.bool()?;
+        self.planes = r.u8()?;
+        self.pixels = r.array()?;
+        let valid = |p: &u8| *p & !ALL_PLANES == 0;
+        if !valid(&self.planes) || !self.pixels.iter().all(valid) {
+            return Err(StateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sprite_memory() -> Memory {
+        let mut memory = Memory::new();
+        memory.load(&[0xFF, 0xFF], 0).unwrap();
+        memory
+    }
+
+    #[test]
+    fn test_draw_wraps() {
+        let mut screen = Screen::new();
+        let memory = sprite_memory();
+        assert_eq!(screen.draw(&memory, 0, 60, 31, 2, false), Ok(false));
+        // right edge of the first row
+        assert_eq!(screen.buffer()[WIDTH * 31 + 63], 1);
+        // wrapped to the left edge
+        assert_eq!(screen.buffer()[WIDTH * 31], 1);
+        // wrapped to the top
+        assert_eq!(screen.buffer()[60], 1);
+        assert_eq!(screen.buffer()[0], 1);
+    }
+
+    #[test]
+    fn test_draw_clips() {
+        let mut screen = Screen::new();
+        let memory = sprite_memory();
+        assert_eq!(screen.draw(&memory, 0, 60, 31, 2, true), Ok(false));
+        assert_eq!(screen.buffer()[WIDTH * 31 + 63], 1);
+        assert_eq!(screen.buffer()[WIDTH * 31], 0);
+        assert_eq!(screen.buffer()[60], 0);
+        assert_eq!(screen.buffer()[0], 0);
+    }
+
+    #[test]
+    fn test_draw_collision() {
+        let mut screen = Screen::new();
+        let memory = sprite_memory();
+        assert_eq!(screen.draw(&memory, 0, 0, 0, 1, false), Ok(false));
+        assert_eq!(screen.draw(&memory, 0, 0, 0, 1, false), Ok(true));
+        assert!(screen.buffer().iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn test_draw_large_sprite() {
+        let mut screen = Screen::new();
+        screen.set_hires(true);
+        let mut memory = Memory::new();
+        memory.load(&[0xFF; 32], 0).unwrap();
+        assert_eq!(screen.draw(&memory, 0, 0, 0, 0, true), Ok(false));
+        assert_eq!(screen.buffer().len(), HIRES_SIZE);
+        assert_eq!(screen.buffer().iter().filter(|p| **p != 0).count(), 16 * 16);
+        assert_eq!(screen.buffer()[HIRES_WIDTH * 15 + 15], 1);
+        assert_eq!(screen.buffer()[HIRES_WIDTH * 16], 0);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut screen = Screen::new();
+        let memory = sprite_memory();
+        screen.draw(&memory, 0, 0, 0, 1, false).unwrap();
+        screen.scroll_down(2);
+        assert_eq!(screen.buffer()[0], 0);
+        assert_eq!(screen.buffer()[WIDTH * 2], 1);
+        screen.scroll_right(4);
+        assert_eq!(screen.buffer()[WIDTH * 2], 0);
+        assert_eq!(screen.buffer()[WIDTH * 2 + 4], 1);
+        assert_eq!(screen.buffer()[WIDTH * 2 + 11], 1);
+        assert_eq!(screen.buffer()[WIDTH * 2 + 12], 0);
+        screen.scroll_left(4);
+        assert_eq!(screen.buffer()[WIDTH * 2], 1);
+        assert_eq!(screen.buffer()[WIDTH * 2 + 8], 0);
+    }
+
+    #[test]
+    fn test_planes() {
+        let mut screen = Screen::new();
+        let mut memory = Memory::new();
+        memory.load(&[0xFF, 0x0F], 0).unwrap();
+        screen.select_planes(0b11);
+        assert_eq!(screen.draw(&memory, 0, 0, 0, 1, false), Ok(false));
+        assert_eq!(&screen.buffer()[..8], &[1, 1, 1, 1, 3, 3, 3, 3]);
+        // only clears the selected plane
+        screen.select_planes(0b10);
+        screen.clear();
+        assert_eq!(&screen.buffer()[..8], &[1, 1, 1, 1, 1, 1, 1, 1]);
+        screen.select_planes(0b01);
+        screen.scroll_right(4);
+        assert_eq!(&screen.buffer()[..8], &[0, 0, 0, 0, 1, 1, 1, 1]);
     }
 }
@@ -0,0 +1,109 @@
+use crate::state;
+
+/// characters used for pixels by the plane bitmask they're set in
+const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+/// Render a framebuffer as text, one line per row. Unset pixels are `.`, set
+/// pixels are `#`, with `+` and `@` for XO-CHIP's second plane and both
+/// planes.
+pub fn ascii(buffer: &[u8], (width, _): (usize, usize)) -> String {
+    let mut out = String::with_capacity(buffer.len() + buffer.len() / width);
+    for row in buffer.chunks(width) {
+        out.extend(row.iter().map(|p| ASCII_PIXELS[*p as usize & 0b11]));
+        out.push('\n');
+    }
+    out
+}
+
+/// Encode a framebuffer as a plain PBM image, pixels set in any plane are
+/// black.
+pub fn pbm(buffer: &[u8], (width, height): (usize, usize)) -> String {
+    let mut out = format!("P1\n{} {}\n", width, height);
+    for row in buffer.chunks(width) {
+        let bits: Vec<&str> = row
+            .iter()
+            .map(|p| if *p != 0 { "1" } else { "0" })
+            .collect();
+        out.push_str(&bits.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+/// Scale a framebuffer to `out_width` by `out_height`, keeping the plane
+/// bitmasks.
+pub fn scale(
+    buffer: &[u8],
+    (width, height): (usize, usize),
+    (out_width, out_height): (usize, usize),
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(out_width * out_height);
+    for y in 0..out_height {
+        let row = y * height / out_height * width;
+        out.extend((0..out_width).map(|x| buffer[row + x * width / out_width]));
+    }
+    out
+}
+
+/// Render a framebuffer as RGB bytes, scaled to `size`. Pixels are coloured
+/// by `palette`, indexed by the planes they're set in.
+pub fn rgb(
+    buffer: &[u8],
+    resolution: (usize, usize),
+    palette: &[[u8; 3]; 4],
+    size: (usize, usize),
+) -> Vec<u8> {
+    scale(buffer, resolution, size)
+        .iter()
+        .flat_map(|p| palette[*p as usize & 0b11])
+        .collect()
+}
+
+/// A hash of the framebuffer and its resolution, for cheaply comparing
+/// screens.
+pub fn hash(buffer: &[u8], (width, height): (usize, usize)) -> u64 {
+    let mut data = Vec::with_capacity(buffer.len() + 4);
+    data.extend_from_slice(&(width as u16).to_be_bytes());
+    data.extend_from_slice(&(height as u16).to_be_bytes());
+    data.extend_from_slice(buffer);
+    state::hash(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUFFER: [u8; 6] = [0, 1, 2, 3, 0, 1];
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(ascii(&BUFFER, (3, 2)), ".#+\n@.#\n");
+    }
+
+    #[test]
+    fn test_pbm() {
+        assert_eq!(pbm(&BUFFER, (3, 2)), "P1\n3 2\n0 1 1\n1 0 1\n");
+    }
+
+    #[test]
+    fn test_rgb() {
+        let palette = [[0, 0, 0], [1, 1, 1], [2, 2, 2], [3, 3, 3]];
+        assert_eq!(
+            rgb(&BUFFER, (3, 2), &palette, (3, 2)),
+            vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3, 0, 0, 0, 1, 1, 1]
+        );
+        // each pixel becomes 2x2
+        let row = vec![0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1];
+        assert_eq!(
+            rgb(&[0, 1], (2, 1), &palette, (4, 2)),
+            [row.clone(), row].concat()
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(hash(&BUFFER, (3, 2)), hash(&BUFFER, (3, 2)));
+        assert_ne!(hash(&BUFFER, (3, 2)), hash(&BUFFER, (2, 3)));
+        assert_ne!(hash(&BUFFER, (3, 2)), hash(&[0; 6], (3, 2)));
+    }
+}
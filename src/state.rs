@@ -0,0 +1,226 @@
+use std::fmt;
+
+/// identifies a save state file
+const MAGIC: &[u8; 4] = b"C8ST";
+/// bumped whenever the layout of a save state changes
+pub const VERSION: u16 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// the data isn't in the expected format, or is truncated or corrupt
+    InvalidFormat,
+    /// the data was written by an incompatible version of the emulator
+    UnsupportedVersion(u16),
+    /// the data was recorded while running a different ROM
+    RomMismatch,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InvalidFormat => write!(f, "invalid or corrupt file"),
+            StateError::UnsupportedVersion(v) => write!(f, "file version {} is not supported", v),
+            StateError::RomMismatch => write!(f, "file was made with a different ROM"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// FNV-1a hash of some bytes.
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+/// Hash of a ROM, used to make sure a state is loaded into the ROM it was
+/// saved from.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    hash(rom)
+}
+
+/// Builds up a save state, all values are stored big endian.
+#[derive(Debug, Default)]
+pub(crate) struct Writer(Vec<u8>);
+
+impl Writer {
+    pub fn new(rom_hash: u64) -> Self {
+        Self::with_header(MAGIC, VERSION, rom_hash)
+    }
+
+    /// Start a file in another format that shares the save state encoding.
+    pub fn with_header(magic: &[u8; 4], version: u16, rom_hash: u64) -> Self {
+        let mut w = Self(vec![]);
+        w.bytes(magic);
+        w.u16(version);
+        w.u64(rom_hash);
+        w
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.0.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.bytes(&val.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.bytes(&val.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.bytes(&val.to_be_bytes());
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.0.extend_from_slice(val);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Reads back the values written by `Writer`.
+#[derive(Debug)]
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// Check the header, failing if it's not for the given ROM.
+    pub fn new(data: &'a [u8], rom_hash: u64) -> Result<Self, StateError> {
+        Self::with_header(data, MAGIC, VERSION, rom_hash)
+    }
+
+    /// Check the header of a file written with `Writer::with_header`.
+    pub fn with_header(
+        data: &'a [u8],
+        magic: &[u8; 4],
+        version: u16,
+        rom_hash: u64,
+    ) -> Result<Self, StateError> {
+        let mut r = Self(data);
+        if r.bytes(magic.len())? != magic {
+            return Err(StateError::InvalidFormat);
+        }
+        let found = r.u16()?;
+        if found != version {
+            return Err(StateError::UnsupportedVersion(found));
+        }
+        if r.u64()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(r)
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], StateError> {
+        if self.0.len() < n {
+            return Err(StateError::InvalidFormat);
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut arr = [0; N];
+        arr.copy_from_slice(self.bytes(N)?);
+        Ok(arr)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidFormat),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    /// Whether everything has been read.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Fail unless everything has been read.
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::InvalidFormat)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = Writer::new(42);
+        w.u8(1);
+        w.bool(true);
+        w.u16(0xABCD);
+        w.u32(0x1234_5678);
+        w.bytes(&[1, 2, 3]);
+        let data = w.finish();
+
+        let mut r = Reader::new(&data, 42).unwrap();
+        assert_eq!(r.u8(), Ok(1));
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.u16(), Ok(0xABCD));
+        assert_eq!(r.u32(), Ok(0x1234_5678));
+        assert_eq!(r.array(), Ok([1, 2, 3]));
+        assert_eq!(r.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_header() {
+        let data = Writer::new(42).finish();
+        assert!(Reader::new(&data, 42).is_ok());
+        assert_eq!(Reader::new(&data, 43).unwrap_err(), StateError::RomMismatch);
+        assert_eq!(
+            Reader::new(&data[..4], 42).unwrap_err(),
+            StateError::InvalidFormat
+        );
+        assert_eq!(
+            Reader::new(b"nope, not a state", 42).unwrap_err(),
+            StateError::InvalidFormat
+        );
+
+        let mut data = data;
+        data[5] = 99;
+        assert_eq!(
+            Reader::new(&data, 42).unwrap_err(),
+            StateError::UnsupportedVersion(99)
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = Writer::new(42).finish();
+        let mut r = Reader::new(&data, 42).unwrap();
+        assert_eq!(r.u8(), Err(StateError::InvalidFormat));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::frontend;
+use crate::keymap::Keymap;
+use chip8::Machine;
+use clap::ValueEnum;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use sdl2::keyboard::Scancode;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How long a key stays pressed after the terminal reports it, when it can't
+/// report releases. Repeats while the key is held keep it pressed for a
+/// shorter time after each one.
+const KEY_HOLD: Duration = Duration::from_millis(200);
+const KEY_REPEAT_HOLD: Duration = Duration::from_millis(100);
+
+/// Braille dot bits by their (x, y) position in a cell.
+const BRAILLE_DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+
+/// How pixels are packed into the terminal's character cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Cells {
+    /// two pixels a cell, the top one drawn with `▀` and the bottom one as
+    /// the background
+    HalfBlock,
+    /// eight pixels a cell as Braille dots, in a single colour
+    Braille,
+}
+
+impl Cells {
+    /// Pixels in a cell, (width, height).
+    fn size(self) -> (usize, usize) {
+        match self {
+            Cells::HalfBlock => (1, 2),
+            Cells::Braille => (2, 4),
+        }
+    }
+
+    /// The character for the cell at (`col`, `row`), with the plane bitmasks
+    /// coloring its foreground and background.
+    fn cell(self, buffer: &[u8], width: usize, col: usize, row: usize) -> (char, u8, u8) {
+        let (cell_width, cell_height) = self.size();
+        let pixel =
+            |x: usize, y: usize| buffer[(row * cell_height + y) * width + col * cell_width + x];
+        match self {
+            Cells::HalfBlock => ('▀', pixel(0, 0), pixel(0, 1)),
+            Cells::Braille => {
+                let mut dots = 0;
+                // the cell takes the colour most of its pixels are set in
+                let mut counts = [0; 4];
+                for (x, column) in BRAILLE_DOTS.iter().enumerate() {
+                    for (y, dot) in column.iter().enumerate() {
+                        let p = pixel(x, y) as usize & 0b11;
+                        if p != 0 {
+                            dots |= dot;
+                            counts[p] += 1;
+                        }
+                    }
+                }
+                let fg = (1..4).rev().max_by_key(|p| counts[*p]).unwrap();
+                let c = char::from_u32(0x2800 + dots).unwrap();
+                (c, fg as u8, 0)
+            }
+        }
+    }
+}
+
+/// Render a framebuffer as lines of text with ANSI colours, pixels are
+/// coloured by `palette`, indexed by the planes they're set in. Lines end in
+/// `\r\n` as the terminal is in raw mode.
+pub fn render(
+    buffer: &[u8],
+    (width, height): (usize, usize),
+    palette: &[[u8; 3]; 4],
+    cells: Cells,
+) -> String {
+    let (cell_width, cell_height) = cells.size();
+    let mut out = String::new();
+    for row in 0..height / cell_height {
+        let mut colours = None;
+        for col in 0..width / cell_width {
+            let (c, fg, bg) = cells.cell(buffer, width, col, row);
+            // only change colour when it differs from the last cell
+            if colours != Some((fg, bg)) {
+                let [fr, fg_, fb] = palette[fg as usize & 0b11];
+                let [br, bg_, bb] = palette[bg as usize & 0b11];
+                let _ = write!(out, "\x1b[38;2;{fr};{fg_};{fb};48;2;{br};{bg_};{bb}m");
+                colours = Some((fg, bg));
+            }
+            out.push(c);
+        }
+        out.push_str("\x1b[0m\r\n");
+    }
+    out
+}
+
+/// The scancode for a key the terminal reports, so keymaps work as they do
+/// in the window. Terminals send characters rather than key positions, so
+/// these are the keys that type them on a US layout.
+fn scancode(code: KeyCode) -> Option<Scancode> {
+    use Scancode::*;
+    const LETTERS: [Scancode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [Scancode; 10] = [Num0, Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9];
+    let scancode = match code {
+        KeyCode::Char(c) if c.is_ascii_alphabetic() => {
+            LETTERS[(c.to_ascii_lowercase() as u8 - b'a') as usize]
+        }
+        KeyCode::Char(c) if c.is_ascii_digit() => DIGITS[(c as u8 - b'0') as usize],
+        KeyCode::Char(' ') => Space,
+        KeyCode::Char('-') => Minus,
+        KeyCode::Char('=') => Equals,
+        KeyCode::Char('[') => LeftBracket,
+        KeyCode::Char(']') => RightBracket,
+        KeyCode::Char('\\') => Backslash,
+        KeyCode::Char(';') => Semicolon,
+        KeyCode::Char('\'') => Apostrophe,
+        KeyCode::Char('`') => Grave,
+        KeyCode::Char(',') => Comma,
+        KeyCode::Char('.') => Period,
+        KeyCode::Char('/') => Slash,
+        KeyCode::Enter => Return,
+        KeyCode::Esc => Escape,
+        KeyCode::Backspace => Backspace,
+        KeyCode::Tab => Tab,
+        KeyCode::Insert => Insert,
+        KeyCode::Delete => Delete,
+        KeyCode::Home => Home,
+        KeyCode::End => End,
+        KeyCode::PageUp => PageUp,
+        KeyCode::PageDown => PageDown,
+        KeyCode::Up => Up,
+        KeyCode::Down => Down,
+        KeyCode::Left => Left,
+        KeyCode::Right => Right,
+        _ => return None,
+    };
+    Some(scancode)
+}
+
+/// Most terminals only report key presses, repeating them while a key is
+/// held down. Without release events a key is released once the terminal
+/// stops repeating it.
+#[derive(Debug, Default)]
+struct HeldKeys {
+    /// when each key is released, `None` if it isn't held
+    deadlines: [Option<Instant>; 16],
+}
+
+impl HeldKeys {
+    /// Hold `key` after the terminal reports it at `now`, returning whether
+    /// it was newly pressed.
+    fn press(&mut self, key: u8, now: Instant) -> bool {
+        let deadline = &mut self.deadlines[key as usize];
+        let pressed = deadline.is_none();
+        let hold = if pressed { KEY_HOLD } else { KEY_REPEAT_HOLD };
+        *deadline = Some(now + hold);
+        pressed
+    }
+
+    /// Release the keys that haven't been repeated in time, returning them.
+    fn expire(&mut self, now: Instant) -> Vec<u8> {
+        let mut released = vec![];
+        for (key, deadline) in self.deadlines.iter_mut().enumerate() {
+            if deadline.is_some_and(|d| d <= now) {
+                *deadline = None;
+                released.push(key as u8);
+            }
+        }
+        released
+    }
+}
+
+/// Puts the terminal in raw mode on the alternate screen, restoring it when
+/// dropped so it's left usable however the frontend exits.
+struct Terminal {
+    /// whether the terminal reports key releases
+    releases: bool,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(
+            io::stdout(),
+            EnterAlternateScreen,
+            Hide,
+            Clear(ClearType::All)
+        )?;
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if releases {
+            execute!(
+                io::stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(Self { releases })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.releases {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Run `machine` in the terminal until it exits or escape or Ctrl+C is
+/// pressed. The screen is redrawn when it changes, and the terminal bell
+/// rings when the sound timer starts.
+pub fn run(
+    machine: &mut Machine,
+    keymap: &Keymap,
+    palette: &[[u8; 3]; 4],
+    cells: Cells,
+    rom_path: &PathBuf,
+) -> Result<(), String> {
+    let terminal = Terminal::enter().map_err(|e| e.to_string())?;
+    let mut out = io::stdout().lock();
+    let mut held = HeldKeys::default();
+    let mut pacer = frontend::pacer();
+    let mut last_update = Instant::now();
+    // paused with insert, end then runs a single frame
+    let mut pause = false;
+    let mut frame_advance = false;
+    let mut beeping = false;
+    let mut status = String::new();
+    let mut drawn = String::new();
+    'running: loop {
+        let now = Instant::now();
+        while event::poll(Duration::ZERO).map_err(|e| e.to_string())? {
+            let Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind,
+                ..
+            }) = event::read().map_err(|e| e.to_string())?
+            else {
+                continue;
+            };
+            if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+                break 'running;
+            }
+            let Some(scancode) = scancode(code) else {
+                continue;
+            };
+            match scancode {
+                Scancode::Escape => break 'running,
+                _ if kind != KeyEventKind::Press => {}
+                Scancode::Insert => {
+                    pause = !pause;
+                    status = if pause { "paused" } else { "resumed" }.to_string();
+                }
+                Scancode::End if pause => frame_advance = true,
+                Scancode::Delete => {
+                    let hard = modifiers.contains(KeyModifiers::SHIFT);
+                    status = match frontend::reset(machine, rom_path, hard) {
+                        Ok(message) => message.to_string(),
+                        Err(e) => e,
+                    };
+                }
+                _ => {}
+            }
+            let Some(k) = keymap.key(scancode) else {
+                continue;
+            };
+            match kind {
+                KeyEventKind::Press if !terminal.releases => {
+                    if held.press(k, now) {
+                        machine.press_key(k, true);
+                    }
+                }
+                KeyEventKind::Press => machine.press_key(k, true),
+                KeyEventKind::Release => machine.press_key(k, false),
+                KeyEventKind::Repeat => {}
+            }
+        }
+        for k in held.expire(now) {
+            machine.press_key(k, false);
+        }
+
+        let due = pacer.advance(now - last_update);
+        let frames = if pause { frame_advance as u32 } else { due };
+        frame_advance = false;
+        last_update = now;
+        for _ in 0..frames {
+            machine
+                .run_frame()
+                .map_err(|e| format!("CPU error: {}", e))?;
+            if machine.has_exited() {
+                break 'running;
+            }
+        }
+
+        let sound = machine.sound_active() && !pause;
+        if sound && !beeping {
+            let _ = out.write_all(b"\x07");
+        }
+        beeping = sound;
+
+        let mut frame = render(machine.framebuffer(), machine.resolution(), palette, cells);
+        frame.push_str(&status);
+        if frame != drawn {
+            queue!(out, MoveTo(0, 0)).map_err(|e| e.to_string())?;
+            out.write_all(frame.as_bytes()).map_err(|e| e.to_string())?;
+            // the screen may have shrunk when leaving hires mode
+            queue!(out, Clear(ClearType::FromCursorDown)).map_err(|e| e.to_string())?;
+            drawn = frame;
+        }
+        out.flush().map_err(|e| e.to_string())?;
+
+        std::thread::sleep(pacer.until_next_frame());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTE: [[u8; 3]; 4] = [[0, 0, 0], [255, 255, 255], [255, 0, 0], [255, 255, 0]];
+
+    #[test]
+    fn test_render_half_block() {
+        // 2x2, the left column is white over black and the right black over
+        // yellow
+        let text = render(&[1, 0, 0, 3], (2, 2), &PALETTE, Cells::HalfBlock);
+        assert_eq!(
+            text,
+            "\x1b[38;2;255;255;255;48;2;0;0;0m▀\
+             \x1b[38;2;0;0;0;48;2;255;255;0m▀\x1b[0m\r\n"
+        );
+        // neighbouring cells in the same colours share an escape code
+        let text = render(&[1, 1, 0, 0], (2, 2), &PALETTE, Cells::HalfBlock);
+        assert_eq!(text.matches("\x1b[38").count(), 1);
+    }
+
+    #[test]
+    fn test_render_braille() {
+        let cell = |buffer: &[u8]| Cells::Braille.cell(buffer, 2, 0, 0);
+        assert_eq!(cell(&[0; 8]), ('\u{2800}', 1, 0));
+        assert_eq!(cell(&[1; 8]), ('⣿', 1, 0));
+        // top left and bottom right, mostly in the second plane
+        assert_eq!(cell(&[2, 0, 0, 0, 0, 0, 0, 2]), ('⢁', 2, 0));
+        let text = render(&[1; 16], (4, 4), &PALETTE, Cells::Braille);
+        assert!(text.ends_with("⣿⣿\x1b[0m\r\n"));
+    }
+
+    #[test]
+    fn test_scancode() {
+        assert_eq!(scancode(KeyCode::Char('q')), Some(Scancode::Q));
+        assert_eq!(scancode(KeyCode::Char('Q')), Some(Scancode::Q));
+        assert_eq!(scancode(KeyCode::Char('4')), Some(Scancode::Num4));
+        assert_eq!(scancode(KeyCode::Up), Some(Scancode::Up));
+        assert_eq!(scancode(KeyCode::Char('é')), None);
+    }
+
+    #[test]
+    fn test_held_keys() {
+        let start = Instant::now();
+        let mut held = HeldKeys::default();
+        assert!(held.press(0x5, start));
+        assert_eq!(held.expire(start + KEY_HOLD / 2), vec![]);
+        // a repeat keeps it held
+        let repeat = start + KEY_HOLD * 3 / 4;
+        assert!(!held.press(0x5, repeat));
+        assert_eq!(held.expire(start + KEY_HOLD), vec![]);
+        assert_eq!(held.expire(repeat + KEY_REPEAT_HOLD), vec![0x5]);
+        assert!(held.press(0x5, start + KEY_HOLD * 2));
+    }
+}
@@ -0,0 +1,201 @@
+//! Runs the well known conformance ROMs (the IBM logo, corax+, flags, quirks
+//! and keypad tests from Timendus' CHIP-8 test suite) headlessly and compares
+//! the final screen against golden images in `tests/golden`.
+//!
+//! The ROMs aren't part of the repository, `tests/roms/fetch.sh` downloads
+//! them. The golden images are plain PBM files captured from a reference
+//! emulator, not from this one, so the suite catches bugs it would otherwise
+//! agree with itself on. As neither is in the repository the tests are
+//! ignored by default, run them with
+//! `cargo test --test conformance -- --ignored`.
+//!
+//! The test ROMs label their checks on screen, so a mismatch prints both
+//! screens along with the rows that differ, which shows the failed opcode or
+//! quirk.
+
+use chip8::{snapshot, Machine, Quirks};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// enough for every ROM to finish, even when waiting for the display
+const FRAMES: u32 = 600;
+
+struct Suite<'a> {
+    /// name of the ROM in `tests/roms`, without the `.ch8`
+    rom: &'static str,
+    /// name of the image in `tests/golden`, without the `.pbm`
+    golden: &'static str,
+    quirks: Quirks,
+    /// keys pressed or released before a frame, as (frame, key, pressed)
+    inputs: &'a [(u32, u8, bool)],
+}
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+fn read(path: &Path, hint: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| panic!("{}: {}, {}", path.display(), e, hint))
+}
+
+/// Parse a plain (P1) PBM image into rows of pixels, set pixels are true.
+fn parse_pbm(text: &str) -> Option<Vec<Vec<bool>>> {
+    let mut tokens = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split_whitespace());
+    if tokens.next()? != "P1" {
+        return None;
+    }
+    let width: usize = tokens.next()?.parse().ok()?;
+    let height: usize = tokens.next()?.parse().ok()?;
+    // pixels can be run together without whitespace
+    let pixels: Vec<bool> = tokens
+        .flat_map(|t| t.chars())
+        .map(|c| match c {
+            '0' => Some(false),
+            '1' => Some(true),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    if width == 0 || pixels.len() != width * height {
+        return None;
+    }
+    Some(pixels.chunks(width).map(|row| row.to_vec()).collect())
+}
+
+/// Indices of the rows that differ between the two images.
+fn differing_rows(expected: &[Vec<bool>], actual: &[Vec<bool>]) -> Vec<usize> {
+    (0..expected.len().max(actual.len()))
+        .filter(|y| expected.get(*y) != actual.get(*y))
+        .collect()
+}
+
+fn ascii(rows: &[Vec<bool>]) -> String {
+    rows.iter()
+        .map(|row| row.iter().map(|p| if *p { '#' } else { '.' }).collect())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn run(suite: &Suite) {
+    let rom_path = tests_dir().join("roms").join(format!("{}.ch8", suite.rom));
+    let rom = read(&rom_path, "run tests/roms/fetch.sh");
+    let mut machine = Machine::with_seed(&rom, suite.quirks, 0).unwrap();
+    for frame in 0..FRAMES {
+        for (_, key, pressed) in suite.inputs.iter().filter(|(f, ..)| *f == frame) {
+            machine.press_key(*key, *pressed);
+        }
+        if let Err(e) = machine.run_frame() {
+            panic!("{}: CPU error: {}", suite.golden, e);
+        }
+    }
+    let actual = snapshot::pbm(machine.framebuffer(), machine.resolution());
+    let actual = parse_pbm(&actual).unwrap();
+
+    let golden_path = tests_dir()
+        .join("golden")
+        .join(format!("{}.pbm", suite.golden));
+    let golden = read(&golden_path, "capture it from a reference emulator");
+    let expected = parse_pbm(&String::from_utf8_lossy(&golden))
+        .unwrap_or_else(|| panic!("{}: not a plain PBM image", golden_path.display()));
+    let rows = differing_rows(&expected, &actual);
+    if !rows.is_empty() {
+        panic!(
+            "{}: rows {:?} differ\nexpected:\n{}\nactual:\n{}",
+            suite.golden,
+            rows,
+            ascii(&expected),
+            ascii(&actual)
+        );
+    }
+}
+
+#[test]
+#[ignore = "needs the test ROMs and golden images, see tests/roms/fetch.sh"]
+fn test_ibm_logo() {
+    run(&Suite {
+        rom: "ibm-logo",
+        golden: "ibm-logo",
+        quirks: Quirks::default(),
+        inputs: &[],
+    });
+}
+
+#[test]
+#[ignore = "needs the test ROMs and golden images, see tests/roms/fetch.sh"]
+fn test_corax() {
+    run(&Suite {
+        rom: "corax+",
+        golden: "corax+",
+        quirks: Quirks::default(),
+        inputs: &[],
+    });
+}
+
+#[test]
+#[ignore = "needs the test ROMs and golden images, see tests/roms/fetch.sh"]
+fn test_flags() {
+    run(&Suite {
+        rom: "flags",
+        golden: "flags",
+        quirks: Quirks::default(),
+        inputs: &[],
+    });
+}
+
+#[test]
+#[ignore = "needs the test ROMs and golden images, see tests/roms/fetch.sh"]
+fn test_quirks() {
+    // the ROM asks which platform to test, chosen with keys 1 to 3
+    let platforms = [
+        ("quirks-vip", Quirks::COSMAC_VIP, 0x1),
+        ("quirks-schip", Quirks::SUPER_CHIP, 0x2),
+        ("quirks-xochip", Quirks::XO_CHIP, 0x3),
+    ];
+    for (golden, quirks, key) in platforms {
+        run(&Suite {
+            rom: "quirks",
+            golden,
+            quirks,
+            inputs: &[(10, key, true), (20, key, false)],
+        });
+    }
+}
+
+#[test]
+#[ignore = "needs the test ROMs and golden images, see tests/roms/fetch.sh"]
+fn test_keypad() {
+    run(&Suite {
+        rom: "keypad",
+        golden: "keypad",
+        quirks: Quirks::default(),
+        // picks the FX0A test from the menu, then presses and releases A
+        inputs: &[
+            (10, 0x3, true),
+            (20, 0x3, false),
+            (40, 0xA, true),
+            (50, 0xA, false),
+        ],
+    });
+}
+
+#[test]
+fn test_parse_pbm() {
+    let rows = parse_pbm("P1\n# comment\n3 2\n1 0 1\n010\n").unwrap();
+    assert_eq!(
+        rows,
+        vec![vec![true, false, true], vec![false, true, false]]
+    );
+    assert_eq!(parse_pbm("P1\n3 2\n1 0 1\n"), None);
+    assert_eq!(parse_pbm("P4\n1 1\n1\n"), None);
+}
+
+#[test]
+fn test_differing_rows() {
+    let expected = vec![vec![false; 4]; 3];
+    let mut actual = expected.clone();
+    actual[1][2] = true;
+    assert_eq!(differing_rows(&expected, &actual), vec![1]);
+    assert_eq!(differing_rows(&expected, &expected[..2]), vec![2]);
+}
@@ -0,0 +1,9 @@
+#!/bin/sh
+# Downloads the conformance ROMs from Timendus' CHIP-8 test suite
+# (https://github.com/Timendus/chip8-test-suite) into this directory.
+set -e
+cd "$(dirname "$0")"
+base=https://github.com/Timendus/chip8-test-suite/raw/main/bin
+for rom in 2-ibm-logo 3-corax+ 4-flags 5-quirks 6-keypad; do
+    curl -fsSL -o "${rom#?-}.ch8" "$base/$rom.ch8"
+done